@@ -0,0 +1,164 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Memoization of the common ancestor between two fork lineages, avoiding a
+//! walk of both lineages for every competing block.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use core::core::hash::{Hash, Hashed};
+use heights::CacheStats;
+use types::Lineage;
+
+/// Default number of lineage pairs kept in the cache.
+pub const DEFAULT_CAPACITY: usize = 256;
+
+struct Entries {
+	ancestors: HashMap<(Hash, Hash), u32>,
+	// least recently used first
+	order: VecDeque<(Hash, Hash)>,
+	hits: u64,
+	misses: u64,
+}
+
+/// Least recently used cache of common ancestor branches, keyed by the pair
+/// of lineage hashes. As the key commits to the full lineages, a lineage that
+/// changes (after a compaction for example) hashes differently and can't hit
+/// a stale entry. Entries for a lineage that's gone can still be dropped
+/// explicitly with invalidate.
+pub struct AncestorCache {
+	capacity: usize,
+	entries: Mutex<Entries>,
+}
+
+impl AncestorCache {
+	/// New cache holding up to capacity lineage pairs.
+	pub fn new(capacity: usize) -> AncestorCache {
+		AncestorCache {
+			capacity: capacity,
+			entries: Mutex::new(Entries {
+				ancestors: HashMap::new(),
+				order: VecDeque::new(),
+				hits: 0,
+				misses: 0,
+			}),
+		}
+	}
+
+	/// Common ancestor branch of the two lineages, from the cache if we've
+	/// seen the pair before. Counts as a hit or a miss.
+	pub fn common_ancestor(&self, a: &Lineage, b: &Lineage) -> u32 {
+		let key = pair_key(a.hash(), b.hash());
+		let mut entries = self.entries.lock().unwrap();
+		if let Some(ancestor) = entries.ancestors.get(&key).cloned() {
+			entries.hits += 1;
+			entries.order.retain(|k| *k != key);
+			entries.order.push_back(key);
+			return ancestor;
+		}
+		entries.misses += 1;
+
+		let ancestor = a.common_ancestor(b);
+		if self.capacity > 0 {
+			if entries.order.len() >= self.capacity {
+				if let Some(oldest) = entries.order.pop_front() {
+					entries.ancestors.remove(&oldest);
+				}
+			}
+			entries.ancestors.insert(key, ancestor);
+			entries.order.push_back(key);
+		}
+		ancestor
+	}
+
+	/// Drops all the entries involving the lineage with the provided hash.
+	pub fn invalidate(&self, lineage_h: &Hash) {
+		let mut entries = self.entries.lock().unwrap();
+		entries.ancestors.retain(|k, _| k.0 != *lineage_h && k.1 != *lineage_h);
+		entries.order.retain(|k| k.0 != *lineage_h && k.1 != *lineage_h);
+	}
+
+	/// Number of lineage pairs currently cached.
+	pub fn len(&self) -> usize {
+		self.entries.lock().unwrap().order.len()
+	}
+
+	/// Hits and misses so far, along with the number of pairs cached.
+	pub fn stats(&self) -> CacheStats {
+		let entries = self.entries.lock().unwrap();
+		CacheStats {
+			hits: entries.hits,
+			misses: entries.misses,
+			len: entries.order.len(),
+		}
+	}
+}
+
+impl Default for AncestorCache {
+	fn default() -> AncestorCache {
+		AncestorCache::new(DEFAULT_CAPACITY)
+	}
+}
+
+// the common ancestor is symmetric, order the pair so both directions share
+// an entry
+fn pair_key(a: Hash, b: Hash) -> (Hash, Hash) {
+	if a <= b { (a, b) } else { (b, a) }
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn cache_ancestors() {
+		let main = Lineage::new();
+		let fork1 = main.fork(1);
+		let fork2 = fork1.fork(2);
+		let fork3 = main.fork(3);
+
+		let cache = AncestorCache::new(2);
+		assert_eq!(cache.common_ancestor(&fork2, &fork3), 0);
+		// both directions hit the same entry
+		assert_eq!(cache.common_ancestor(&fork3, &fork2), 0);
+		assert_eq!(cache.stats(),
+		           CacheStats {
+			           hits: 1,
+			           misses: 1,
+			           len: 1,
+		           });
+
+		// a third pair evicts the least recently used one
+		assert_eq!(cache.common_ancestor(&fork1, &fork2), 1);
+		assert_eq!(cache.common_ancestor(&main, &fork1), 0);
+		assert_eq!(cache.len(), 2);
+		assert_eq!(cache.common_ancestor(&fork2, &fork3), 0);
+		assert_eq!(cache.stats().misses, 4);
+		assert_eq!(cache.common_ancestor(&main, &fork1), 0);
+		assert_eq!(cache.stats().hits, 2);
+
+		// all the pairs of an invalidated lineage go
+		cache.invalidate(&fork1.hash());
+		assert_eq!(cache.len(), 1);
+		assert_eq!(cache.common_ancestor(&main, &fork1), 0);
+		assert_eq!(cache.stats().misses, 5);
+		assert_eq!(cache.common_ancestor(&fork2, &fork2), 2);
+
+		// nothing cached without capacity
+		let cache = AncestorCache::new(0);
+		assert_eq!(cache.common_ancestor(&fork1, &fork2), 1);
+		assert_eq!(cache.len(), 0);
+	}
+}
@@ -0,0 +1,104 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bloom filter of the block hashes seen, to cheaply tell apart blocks we
+//! surely don't have from the ones we may have.
+
+use std::cmp;
+use std::f64::consts::LN_2;
+
+use byteorder::{ByteOrder, BigEndian};
+
+use core::core::hash::Hash;
+
+/// Default number of blocks the filter is sized for.
+pub const DEFAULT_EXPECTED_BLOCKS: usize = 1_000_000;
+
+/// Default rate of false positives once the expected number of blocks have
+/// been inserted.
+pub const DEFAULT_FALSE_POSITIVE_RATE: f64 = 0.01;
+
+/// Bloom filter over block hashes. Never answers no for an inserted hash,
+/// answers yes for others at about the configured false positive rate until
+/// more than the expected number of hashes are inserted.
+pub struct SeenFilter {
+	bits: Vec<u64>,
+	len: u64,
+	hashes: u32,
+}
+
+impl SeenFilter {
+	/// New empty filter sized for the provided number of blocks and false
+	/// positive rate.
+	pub fn new(expected: usize, false_positive_rate: f64) -> SeenFilter {
+		let n = cmp::max(expected, 1) as f64;
+		let len = (-n * false_positive_rate.ln() / (LN_2 * LN_2)).ceil().max(64.0) as u64;
+		let hashes = ((len as f64 / n) * LN_2).round().max(1.0) as u32;
+		SeenFilter {
+			bits: vec![0; ((len + 63) / 64) as usize],
+			len: len,
+			hashes: hashes,
+		}
+	}
+
+	/// Adds the provided block hash to the filter.
+	pub fn insert(&mut self, h: &Hash) {
+		for pos in self.positions(h) {
+			self.bits[(pos / 64) as usize] |= 1 << (pos % 64);
+		}
+	}
+
+	/// Whether the provided block hash may have been inserted. False means it
+	/// surely wasn't.
+	pub fn probably_contains(&self, h: &Hash) -> bool {
+		self.positions(h).iter().all(|pos| self.bits[(pos / 64) as usize] & (1 << (pos % 64)) != 0)
+	}
+
+	// Bit positions for the hash. Block hashes are already uniformly
+	// distributed so their bytes are used directly, double hashing deriving
+	// as many positions as needed from two of their words.
+	fn positions(&self, h: &Hash) -> Vec<u64> {
+		let a = BigEndian::read_u64(&h.0[0..8]);
+		let b = BigEndian::read_u64(&h.0[8..16]) | 1;
+		(0..self.hashes as u64)
+			.map(|i| a.wrapping_add(i.wrapping_mul(b)) % self.len)
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use core::core::hash::Hashed;
+
+	#[test]
+	fn no_false_negatives() {
+		let mut filter = SeenFilter::new(1000, 0.01);
+		let inserted: Vec<Hash> = (0..1000).map(hash_of).collect();
+		for h in &inserted {
+			filter.insert(h);
+		}
+		assert!(inserted.iter().all(|h| filter.probably_contains(h)));
+
+		// roughly the configured rate for the others
+		let false_positives = (1000..11000).filter(|n| filter.probably_contains(&hash_of(*n))).count();
+		assert!(false_positives < 300);
+	}
+
+	fn hash_of(n: u64) -> Hash {
+		let mut buf = [0; 8];
+		BigEndian::write_u64(&mut buf, n);
+		buf[..].hash()
+	}
+}
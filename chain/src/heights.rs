@@ -0,0 +1,121 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bounded cache of the main chain height of block hashes, remembering the
+//! hashes that aren't on the main chain as well.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use core::core::hash::Hash;
+
+/// Default number of hashes kept in the cache.
+pub const DEFAULT_CAPACITY: usize = 4096;
+
+/// Hits and misses of a cache since it was created, to tune its size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CacheStats {
+	/// Lookups answered from the cache
+	pub hits: u64,
+	/// Lookups that had to go to the store
+	pub misses: u64,
+	/// Number of entries currently cached
+	pub len: usize,
+}
+
+struct Entries {
+	heights: HashMap<Hash, Option<u64>>,
+	// oldest first
+	order: VecDeque<Hash>,
+	hits: u64,
+	misses: u64,
+}
+
+/// Cache of the main chain height of blocks, None for the hashes that aren't
+/// on the main chain (side chain blocks as well as ones we don't have). The
+/// oldest entries get evicted first once full. Entries of the blocks moving
+/// in or out of the main chain have to be updated by the owner.
+pub struct HeightCache {
+	capacity: usize,
+	entries: Mutex<Entries>,
+}
+
+impl HeightCache {
+	/// New cache holding up to capacity hashes, never caching anything with
+	/// a capacity of zero.
+	pub fn new(capacity: usize) -> HeightCache {
+		HeightCache {
+			capacity: capacity,
+			entries: Mutex::new(Entries {
+				heights: HashMap::new(),
+				order: VecDeque::new(),
+				hits: 0,
+				misses: 0,
+			}),
+		}
+	}
+
+	/// The cached height of the provided hash, the outer option being None if
+	/// the hash isn't cached. Counts as a hit or a miss.
+	pub fn get(&self, h: &Hash) -> Option<Option<u64>> {
+		let mut entries = self.entries.lock().unwrap();
+		let cached = entries.heights.get(h).cloned();
+		if cached.is_some() {
+			entries.hits += 1;
+		} else {
+			entries.misses += 1;
+		}
+		cached
+	}
+
+	/// Caches the main chain height of the provided hash, or its absence from
+	/// the main chain, replacing any previous entry.
+	pub fn insert(&self, h: Hash, height: Option<u64>) {
+		if self.capacity == 0 {
+			return;
+		}
+		let mut entries = self.entries.lock().unwrap();
+		if entries.heights.insert(h, height).is_none() {
+			entries.order.push_back(h);
+			if entries.order.len() > self.capacity {
+				if let Some(oldest) = entries.order.pop_front() {
+					entries.heights.remove(&oldest);
+				}
+			}
+		}
+	}
+
+	/// Drops all the entries.
+	pub fn clear(&self) {
+		let mut entries = self.entries.lock().unwrap();
+		entries.heights.clear();
+		entries.order.clear();
+	}
+
+	/// Hits and misses so far.
+	pub fn stats(&self) -> CacheStats {
+		let entries = self.entries.lock().unwrap();
+		CacheStats {
+			hits: entries.hits,
+			misses: entries.misses,
+			len: entries.order.len(),
+		}
+	}
+}
+
+impl Default for HeightCache {
+	fn default() -> HeightCache {
+		HeightCache::new(DEFAULT_CAPACITY)
+	}
+}
@@ -19,6 +19,9 @@
 #![deny(non_snake_case)]
 #![deny(unused_mut)]
 #![warn(missing_docs)]
+#![cfg_attr(all(test, feature = "unstable"), feature(test))]
+#[cfg(all(test, feature = "unstable"))]
+extern crate test;
 
 #[macro_use]
 extern crate bitflags;
@@ -31,11 +34,24 @@ extern crate grin_core as core;
 extern crate grin_store;
 extern crate secp256k1zkp as secp;
 
+pub mod bloom;
+pub mod heights;
+pub mod ancestry;
+pub mod limiter;
+pub mod orphans;
 pub mod pipe;
+#[cfg(feature = "testing")]
+pub mod scenario;
 pub mod store;
 pub mod types;
 
 // Re-export the base interface
 
-pub use types::{ChainStore, Tip, ChainAdapter};
-pub use pipe::{NONE, process_block};
+pub use types::{ChainStore, Tip, HeaderTip, ChainAdapter, BlockPolicy, NoPolicy,
+                DifficultyBounds};
+pub use ancestry::AncestorCache;
+pub use limiter::{PeerId, RateLimiter};
+pub use orphans::OrphanPool;
+pub use pipe::{NONE, add_difficulty_checkpoint, force_head, import_blocks, process_block,
+               process_block_from, ImportReport, PipelineGate, PipelineOpts, StandardVerifier,
+               Verifier};
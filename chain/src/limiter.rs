@@ -0,0 +1,117 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Per source rate limiting of the blocks submitted to the chain, so a single
+//! peer can't monopolize the pipeline.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use time;
+
+/// Opaque identifier of where a block comes from, typically a peer.
+pub type PeerId = u64;
+
+/// Default number of blocks per second we accept from a single source.
+pub const DEFAULT_RATE: u32 = 10;
+
+/// Default number of blocks a source can send at once before being limited.
+pub const DEFAULT_BURST: u32 = 100;
+
+struct Bucket {
+	tokens: f64,
+	last_ms: u64,
+}
+
+/// Token bucket rate limiter keeping a bucket for each source. A bucket
+/// starts full with burst tokens and refills at the configured rate, each
+/// block taking one token.
+pub struct RateLimiter {
+	rate: u32,
+	burst: u32,
+	buckets: Mutex<HashMap<PeerId, Bucket>>,
+}
+
+impl RateLimiter {
+	/// New limiter allowing rate blocks per second for each source, with
+	/// bursts of up to burst blocks.
+	pub fn new(rate: u32, burst: u32) -> RateLimiter {
+		RateLimiter {
+			rate: rate,
+			burst: burst,
+			buckets: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// Whether a new block from the provided source should be accepted,
+	/// consuming a token if so.
+	pub fn allow(&self, source: PeerId) -> bool {
+		self.allow_at(source, time::precise_time_ns() / 1_000_000)
+	}
+
+	/// Same as allow but at the provided time, in milliseconds.
+	pub fn allow_at(&self, source: PeerId, now_ms: u64) -> bool {
+		let mut buckets = self.buckets.lock().unwrap();
+		let burst = self.burst as f64;
+		let bucket = buckets.entry(source).or_insert(Bucket {
+			tokens: burst,
+			last_ms: now_ms,
+		});
+		let elapsed = now_ms.saturating_sub(bucket.last_ms);
+		bucket.tokens = burst.min(bucket.tokens + elapsed as f64 * self.rate as f64 / 1000.0);
+		bucket.last_ms = cmp::max(bucket.last_ms, now_ms);
+		if bucket.tokens >= 1.0 {
+			bucket.tokens -= 1.0;
+			true
+		} else {
+			false
+		}
+	}
+
+	/// Forgets about a source, typically when the peer disconnects.
+	pub fn forget(&self, source: PeerId) {
+		self.buckets.lock().unwrap().remove(&source);
+	}
+}
+
+impl Default for RateLimiter {
+	fn default() -> RateLimiter {
+		RateLimiter::new(DEFAULT_RATE, DEFAULT_BURST)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn limit_burst_then_refill() {
+		let limiter = RateLimiter::new(2, 3);
+		for _ in 0..3 {
+			assert!(limiter.allow_at(1, 1000));
+		}
+		assert!(!limiter.allow_at(1, 1000));
+		// other sources have their own bucket
+		assert!(limiter.allow_at(2, 1000));
+		// 2 blocks per sec, so one more after 500ms
+		assert!(limiter.allow_at(1, 1500));
+		assert!(!limiter.allow_at(1, 1500));
+		// refill never goes above the burst
+		for _ in 0..3 {
+			assert!(limiter.allow_at(1, 60000));
+		}
+		assert!(!limiter.allow_at(1, 60000));
+	}
+}
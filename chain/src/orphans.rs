@@ -0,0 +1,173 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pool of the orphan blocks we received before their parent, kept until the
+//! parent shows up. Optionally persisted so a restart doesn't lose them.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use time;
+
+use core::core::Block;
+use core::core::hash::{Hash, Hashed};
+use types::{ChainStore, Error, Orphan};
+
+/// Maximum number of orphans we keep around.
+pub const MAX_ORPHANS: usize = 100;
+
+/// Time after which an orphan is dropped if its parent never showed up.
+pub const ORPHAN_TTL_SECS: i64 = 20 * 60;
+
+/// Orphan blocks indexed by hash, bounded in size and age. When backed by a
+/// store, orphans are persisted as they're added and removed.
+pub struct OrphanPool {
+	max: usize,
+	ttl_secs: i64,
+	store: Option<Arc<ChainStore>>,
+	orphans: Mutex<HashMap<Hash, Orphan>>,
+}
+
+impl OrphanPool {
+	/// New memory only pool with the provided limits.
+	pub fn new(max: usize, ttl_secs: i64) -> OrphanPool {
+		OrphanPool {
+			max: max,
+			ttl_secs: ttl_secs,
+			store: None,
+			orphans: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// New pool persisted in the provided store, loading the orphans saved
+	/// before a restart that are still within the limits.
+	pub fn persistent(store: Arc<ChainStore>,
+	                  max: usize,
+	                  ttl_secs: i64)
+	                  -> Result<OrphanPool, Error> {
+		let loaded = try!(store.load_orphans(max, ttl_secs));
+		let mut orphans = HashMap::new();
+		for o in loaded {
+			orphans.insert(o.block.hash(), o);
+		}
+		debug!("Loaded {} orphans from store.", orphans.len());
+		Ok(OrphanPool {
+			max: max,
+			ttl_secs: ttl_secs,
+			store: Some(store),
+			orphans: Mutex::new(orphans),
+		})
+	}
+
+	/// Adds an orphan to the pool, evicting the expired ones and the oldest
+	/// one if the pool is full.
+	pub fn add(&self, b: Block) -> Result<(), Error> {
+		let now = time::get_time().sec;
+		let mut orphans = self.orphans.lock().unwrap();
+
+		let cutoff = now - self.ttl_secs;
+		let mut evicted: Vec<Hash> = orphans.iter()
+			.filter(|&(_, o)| o.received <= cutoff)
+			.map(|(h, _)| *h)
+			.collect();
+		if orphans.len() - evicted.len() >= self.max {
+			let oldest = orphans.iter()
+				.filter(|&(h, _)| !evicted.contains(h))
+				.min_by_key(|&(_, o)| o.received)
+				.map(|(h, _)| *h);
+			if let Some(h) = oldest {
+				evicted.push(h);
+			}
+		}
+		for h in evicted {
+			orphans.remove(&h);
+			if let Some(ref store) = self.store {
+				try!(store.delete_orphan(&h));
+			}
+		}
+
+		let orphan = Orphan {
+			block: b,
+			received: now,
+		};
+		if let Some(ref store) = self.store {
+			try!(store.save_orphan(&orphan));
+		}
+		orphans.insert(orphan.block.hash(), orphan);
+		Ok(())
+	}
+
+	/// Removes the orphan with the provided hash from the pool, typically
+	/// once it can be connected, returning it.
+	pub fn remove(&self, h: &Hash) -> Result<Option<Block>, Error> {
+		let removed = self.orphans.lock().unwrap().remove(h);
+		if removed.is_some() {
+			if let Some(ref store) = self.store {
+				try!(store.delete_orphan(h));
+			}
+		}
+		Ok(removed.map(|o| o.block))
+	}
+
+	/// Hashes of the orphans whose parent is the provided block.
+	pub fn children_of(&self, h: &Hash) -> Vec<Hash> {
+		self.orphans
+			.lock()
+			.unwrap()
+			.iter()
+			.filter(|&(_, o)| o.block.header.previous == *h)
+			.map(|(oh, _)| *oh)
+			.collect()
+	}
+
+	/// Hashes of the missing blocks to request for the provided orphan to
+	/// connect, lowest first and capped to the provided maximum. Walks down
+	/// the orphan ancestry through the pool and the headers we already have
+	/// until reaching a block we have or one we know nothing about. Empty if
+	/// the orphan can already be connected.
+	pub fn missing_ancestors(&self,
+	                         store: &ChainStore,
+	                         orphan: &Block,
+	                         max: usize)
+	                         -> Result<Vec<Hash>, Error> {
+		let orphans = self.orphans.lock().unwrap();
+		let mut missing = vec![];
+		let mut h = orphan.header.previous;
+		while !try!(store.contains_block(&h)) {
+			if let Some(o) = orphans.get(&h) {
+				h = o.block.header.previous;
+				continue;
+			}
+			missing.push(h);
+			match store.get_block_header(&h) {
+				Ok(bh) => h = bh.previous,
+				Err(Error::NotFoundErr) => break,
+				Err(e) => return Err(e),
+			}
+		}
+		missing.reverse();
+		missing.truncate(max);
+		Ok(missing)
+	}
+
+	/// Whether the pool contains the provided orphan.
+	pub fn contains(&self, h: &Hash) -> bool {
+		self.orphans.lock().unwrap().contains_key(h)
+	}
+
+	/// Number of orphans in the pool.
+	pub fn len(&self) -> usize {
+		self.orphans.lock().unwrap().len()
+	}
+}
@@ -14,17 +14,21 @@
 
 //! Implementation of the chain block acceptance (or refusal) pipeline.
 
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 
 use secp;
 use time;
 
 use core::consensus;
-use core::core::hash::Hash;
+use core::core::hash::{Hash, Hashed};
 use core::core::{BlockHeader, Block, Proof};
+use core::core::target::Difficulty;
 use core::pow;
+use limiter::{PeerId, RateLimiter};
 use types;
-use types::{Tip, ChainStore, ChainAdapter, NoopAdapter};
+use types::{Tip, HeaderTip, ChainStore, ChainAdapter, NoopAdapter, ReorgTxSet, BlockPolicy,
+            NoPolicy, DifficultyBounds};
 use store;
 
 bitflags! {
@@ -36,12 +40,202 @@ bitflags! {
   }
 }
 
+// Counter for the ids of top level chain operations
+static NEXT_OP_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// New identifier for a top level chain operation (processing a block, a
+/// reorg, an import...), included in all the logs emitted during that
+/// operation so its full trace can be correlated.
+pub fn next_op_id() -> u64 {
+	NEXT_OP_ID.fetch_add(1, Ordering::SeqCst) as u64
+}
+
+/// Verifies the proof of work and the signatures of a block, the costliest
+/// part of its validation. Can be replaced to offload it, to batch or
+/// hardware accelerated verification for example.
+pub trait Verifier {
+	/// Whether the block, extending the provided parent, has a valid proof of
+	/// work. Called with the other header checks.
+	fn verify_header(&self, b: &Block, parent: &BlockHeader) -> Result<(), Error>;
+
+	/// Whether the block sums and signatures are valid. Only called once the
+	/// block has a tip to extend and passed its difficulty checkpoint.
+	fn verify_body(&self, b: &Block) -> Result<(), Error>;
+}
+
+/// Verifier doing the usual checks in process.
+pub struct StandardVerifier {
+	opts: Options,
+}
+
+impl StandardVerifier {
+	/// New verifier, checking the easier proof of work with the EASY_POW
+	/// option.
+	pub fn new(opts: Options) -> StandardVerifier {
+		StandardVerifier { opts: opts }
+	}
+}
+
+impl Verifier for StandardVerifier {
+	fn verify_header(&self, b: &Block, _: &BlockHeader) -> Result<(), Error> {
+		if self.opts.intersects(EASY_POW) {
+			if !pow::verify_size(b, 15) {
+				return Err(Error::InvalidPow);
+			}
+		} else if !pow::verify(b) {
+			return Err(Error::InvalidPow);
+		}
+		Ok(())
+	}
+
+	fn verify_body(&self, b: &Block) -> Result<(), Error> {
+		// TODO check tx merkle tree
+		let curve = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+		b.verify(&curve).map_err(&Error::InvalidBlockProof)
+	}
+}
+
+/// Lets block processing be paused without shutting down, for maintenance
+/// like a compaction or a backup that needs a store not being written to.
+/// While paused, blocks processed with the gate in their PipelineOpts are
+/// refused with `Error::Paused` and reads are unaffected.
+pub struct PipelineGate {
+	// whether paused and the number of blocks being processed
+	state: Mutex<(bool, usize)>,
+	drained: Condvar,
+}
+
+impl PipelineGate {
+	/// New open gate.
+	pub fn new() -> PipelineGate {
+		PipelineGate {
+			state: Mutex::new((false, 0)),
+			drained: Condvar::new(),
+		}
+	}
+
+	/// Refuses new blocks and waits for the ones being processed to be done,
+	/// leaving the store in a consistent state once it returns.
+	pub fn pause(&self) {
+		let mut state = self.state.lock().unwrap();
+		state.0 = true;
+		while state.1 > 0 {
+			state = self.drained.wait(state).unwrap();
+		}
+		info!("Block processing paused.");
+	}
+
+	/// Accepts blocks again.
+	pub fn resume(&self) {
+		self.state.lock().unwrap().0 = false;
+		info!("Block processing resumed.");
+	}
+
+	/// Whether block processing is currently paused.
+	pub fn is_paused(&self) -> bool {
+		self.state.lock().unwrap().0
+	}
+
+	fn enter(&self) -> Result<GateGuard, Error> {
+		let mut state = self.state.lock().unwrap();
+		if state.0 {
+			return Err(Error::Paused);
+		}
+		state.1 += 1;
+		Ok(GateGuard { gate: self })
+	}
+}
+
+impl Default for PipelineGate {
+	fn default() -> PipelineGate {
+		PipelineGate::new()
+	}
+}
+
+// Counts a block as being processed until dropped
+struct GateGuard<'a> {
+	gate: &'a PipelineGate,
+}
+
+impl<'a> Drop for GateGuard<'a> {
+	fn drop(&mut self) {
+		let mut state = self.gate.state.lock().unwrap();
+		state.1 -= 1;
+		if state.1 == 0 {
+			self.gate.drained.notify_all();
+		}
+	}
+}
+
+/// How the pipeline processes blocks beyond the validation options: the local
+/// policy, the difficulty bounds, the proof of work and signatures verifier,
+/// and optionally a rate limiter for the blocks from our peers and a gate to
+/// pause processing. Built from the defaults, overriding what's needed.
+#[derive(Clone)]
+pub struct PipelineOpts {
+	opts: Options,
+	policy: Arc<BlockPolicy>,
+	bounds: DifficultyBounds,
+	verifier: Arc<Verifier>,
+	limiter: Option<Arc<RateLimiter>>,
+	gate: Option<Arc<PipelineGate>>,
+}
+
+impl PipelineOpts {
+	/// Pipeline with the provided validation options, admitting all valid
+	/// blocks within the default difficulty bounds, verified in process.
+	pub fn new(opts: Options) -> PipelineOpts {
+		PipelineOpts {
+			opts: opts,
+			policy: Arc::new(NoPolicy {}),
+			bounds: DifficultyBounds::default(),
+			verifier: Arc::new(StandardVerifier::new(opts)),
+			limiter: None,
+			gate: None,
+		}
+	}
+
+	/// Also refuses the valid blocks our local policy doesn't admit.
+	pub fn with_policy(mut self, policy: Arc<BlockPolicy>) -> PipelineOpts {
+		self.policy = policy;
+		self
+	}
+
+	/// Checks the block difficulty against the provided bounds instead of
+	/// the default ones.
+	pub fn with_bounds(mut self, bounds: DifficultyBounds) -> PipelineOpts {
+		self.bounds = bounds;
+		self
+	}
+
+	/// Leaves the proof of work and signatures to the provided verifier.
+	pub fn with_verifier(mut self, verifier: Arc<Verifier>) -> PipelineOpts {
+		self.verifier = verifier;
+		self
+	}
+
+	/// Rejects right away the blocks from a source exceeding its quota, see
+	/// process_block_from.
+	pub fn with_rate_limiter(mut self, limiter: Arc<RateLimiter>) -> PipelineOpts {
+		self.limiter = Some(limiter);
+		self
+	}
+
+	/// Refuses blocks while the provided gate is paused.
+	pub fn with_gate(mut self, gate: Arc<PipelineGate>) -> PipelineOpts {
+		self.gate = Some(gate);
+		self
+	}
+}
+
+
 /// Contextual information required to process a new block and either reject or
 /// accept it.
 pub struct BlockContext {
-	opts: Options,
+	op_id: u64,
 	store: Arc<ChainStore>,
 	adapter: Arc<ChainAdapter>,
+	pipeline: PipelineOpts,
 	head: Tip,
 	tip: Option<Tip>,
 }
@@ -62,45 +256,249 @@ pub enum Error {
 	InvalidBlockTime,
 	/// Internal issue when trying to save or load data from store
 	StoreErr(types::Error),
+	/// The source of the block sent us too many blocks recently
+	RateLimited,
+	/// Block processing is paused, the block should be retried once resumed
+	Paused,
+	/// The chain total difficulty at the provided height doesn't match the
+	/// checkpointed one (expected, got)
+	DifficultyCheckpointMismatch(u64, Difficulty, Difficulty),
+	/// The block is valid but our local block policy refused it
+	PolicyRejected(String),
+	/// The fork has more work than our head but disagrees with the
+	/// checkpointed total difficulty at the provided height (expected, got),
+	/// so it can't become the head
+	CheckpointMismatch(u64, Difficulty, Difficulty),
+	/// The block difficulty is outside of the sanity bounds, or changes too
+	/// much from its parent's
+	DifficultyOutOfRange {
+		/// Difficulty the block declares
+		got: Difficulty,
+		/// Lowest difficulty acceptable for the block
+		min: Difficulty,
+		/// Highest difficulty acceptable for the block
+		max: Difficulty,
+	},
+}
+
+/// What happened to each block of a bulk import.
+#[derive(Debug)]
+pub struct ImportReport {
+	/// Identifier of the import operation, tagging the logs of all the
+	/// blocks it processed
+	pub op_id: u64,
+	/// Number of blocks added to the chain, main or side
+	pub accepted: u64,
+	/// Number of blocks we already had
+	pub duplicates: u64,
+	/// Blocks whose parent we don't have, that can be imported again once
+	/// the missing blocks are
+	pub orphaned: Vec<Hash>,
+	/// Blocks refused as invalid, with the reason
+	pub rejected: Vec<(Hash, Error)>,
 }
 
+
 /// Runs the block processing pipeline, including validation and finding a
 /// place for the new block in the chain. Returns the new
-/// chain head if updated.
+/// chain head if updated, None if the block only extended a side chain.
 pub fn process_block(b: &Block,
                      store: Arc<ChainStore>,
                      adapter: Arc<ChainAdapter>,
-                     opts: Options)
+                     pipeline: &PipelineOpts)
                      -> Result<Option<Tip>, Error> {
+	process_block_op(next_op_id(), b, store, adapter, pipeline)
+}
+
+// Runs the block processing pipeline as part of the provided operation
+fn process_block_op(op_id: u64,
+                    b: &Block,
+                    store: Arc<ChainStore>,
+                    adapter: Arc<ChainAdapter>,
+                    pipeline: &PipelineOpts)
+                    -> Result<Option<Tip>, Error> {
 	// TODO should just take a promise for a block with a full header so we don't
 	// spend resources reading the full block when its header is invalid
 
+	let _guard = match pipeline.gate {
+		Some(ref gate) => Some(try!(gate.enter())),
+		None => None,
+	};
 	let head = try!(store.head().map_err(&Error::StoreErr));
 
 	let mut ctx = BlockContext {
-		opts: opts,
+		op_id: op_id,
 		store: store,
 		adapter: adapter,
+		pipeline: pipeline.clone(),
 		head: head,
 		tip: None,
 	};
 
-	info!("Starting validation pipeline for block {} at {}.",
+	info!("[op {}] Starting validation pipeline for block {} at {}.",
+	      ctx.op_id,
 	      b.hash(),
 	      b.header.height);
-	try!(check_known(b.hash(), &mut ctx));
-	try!(validate_header(&b, &mut ctx));
-	try!(set_tip(&b.header, &mut ctx));
-	try!(validate_block(b, &mut ctx));
-	info!("Block at {} with hash {} is valid, going to save and append.",
+	let res = validate_and_add(b, &mut ctx);
+	if let Err(ref e) = res {
+		debug!("[op {}] Block {} at {} refused: {:?}",
+		       ctx.op_id,
+		       b.hash(),
+		       b.header.height,
+		       e);
+	}
+	res
+}
+
+fn validate_and_add(b: &Block, ctx: &mut BlockContext) -> Result<Option<Tip>, Error> {
+	try!(check_known(b.hash(), ctx));
+	try!(validate_header(&b, ctx));
+	try!(set_tip(&b.header, ctx));
+	try!(check_difficulty_checkpoint(&b.header, ctx));
+	try!(validate_block(b, ctx));
+	try!(check_policy(b, ctx));
+	info!("[op {}] Block at {} with hash {} is valid, going to save and append.",
+	      ctx.op_id,
 	      b.header.height,
 	      b.hash());
-	try!(add_block(b, &mut ctx));
+	try!(add_block(b, ctx));
 	// TODO a global lock should be set before that step or even earlier
-	try!(update_tips(&mut ctx));
+	update_tips(ctx)
+}
 
-	// TODO make sure we always return the head, and not a fork that just got longer
-	Ok(ctx.tip)
+/// Runs the block processing pipeline for a block received from the provided
+/// source, rejecting it right away if that source exceeded its quota with
+/// the rate limiter of the pipeline, if any.
+pub fn process_block_from(b: &Block,
+                          source: PeerId,
+                          store: Arc<ChainStore>,
+                          adapter: Arc<ChainAdapter>,
+                          pipeline: &PipelineOpts)
+                          -> Result<Option<Tip>, Error> {
+	let op_id = next_op_id();
+	if let Some(ref limiter) = pipeline.limiter {
+		if !limiter.allow(source) {
+			debug!("[op {}] Rate limiting block {} from {}.", op_id, b.hash(), source);
+			return Err(Error::RateLimited);
+		}
+	}
+	process_block_op(op_id, b, store, adapter, pipeline)
+}
+
+/// Processes the provided blocks in order, going on past the ones that are
+/// duplicates, orphans or invalid and reporting what happened to each. A
+/// failure of the store or an inconsistency in it stops the import.
+pub fn import_blocks(blocks: &[Block],
+                     store: Arc<ChainStore>,
+                     adapter: Arc<ChainAdapter>,
+                     pipeline: &PipelineOpts)
+                     -> Result<ImportReport, Error> {
+	let mut report = ImportReport {
+		op_id: next_op_id(),
+		accepted: 0,
+		duplicates: 0,
+		orphaned: vec![],
+		rejected: vec![],
+	};
+	info!("[op {}] Importing {} blocks.", report.op_id, blocks.len());
+	for b in blocks {
+		let h = b.hash();
+		if try!(store.contains_block(&h).map_err(&Error::StoreErr)) {
+			debug!("[op {}] Block {} already known.", report.op_id, h);
+			report.duplicates += 1;
+			continue;
+		}
+		// only a missing parent can get connected by importing more blocks
+		match store.get_block_header(&b.header.previous) {
+			Ok(_) => {}
+			Err(types::Error::NotFoundErr) => {
+				debug!("[op {}] Block {} is an orphan.", report.op_id, h);
+				report.orphaned.push(h);
+				continue;
+			}
+			Err(e) => return Err(Error::StoreErr(e)),
+		}
+		match process_block_op(report.op_id, b, store.clone(), adapter.clone(), pipeline) {
+			Ok(_) => report.accepted += 1,
+			Err(Error::Unfit(ref reason)) if reason == "already known" => report.duplicates += 1,
+			Err(Error::StoreErr(e)) => {
+				if !refuses_block(&e) {
+					return Err(Error::StoreErr(e));
+				}
+				report.rejected.push((h, Error::StoreErr(e)));
+			}
+			Err(e) => report.rejected.push((h, e)),
+		}
+	}
+	info!("[op {}] Imported {} blocks, {} duplicates, {} orphans and {} rejected.",
+	      report.op_id,
+	      report.accepted,
+	      report.duplicates,
+	      report.orphaned.len(),
+	      report.rejected.len());
+	Ok(report)
+}
+
+// Whether a store error is about the block being processed rather than the
+// store itself, so the import can go on with the next blocks
+fn refuses_block(e: &types::Error) -> bool {
+	match *e {
+		types::Error::DiffTooLarge |
+		types::Error::BlockPruned(_) |
+		types::Error::HashCollision(_) |
+		types::Error::ForkBelowFinalized(_) |
+		types::Error::DifficultyOverflow(_) |
+		types::Error::NonContiguousHeight { .. } => true,
+		_ => false,
+	}
+}
+
+/// Forces the head to the tracked fork ending with the provided block,
+/// overriding fork choice, and lets the adapter know of the transactions
+/// affected. For manual intervention only, see `ChainStore::force_head`.
+pub fn force_head(h: &Hash,
+                  store: Arc<ChainStore>,
+                  adapter: Arc<ChainAdapter>)
+                  -> Result<Tip, Error> {
+	let head = try!(store.head().map_err(&Error::StoreErr));
+
+	let mut ctx = BlockContext {
+		op_id: next_op_id(),
+		store: store,
+		adapter: adapter,
+		pipeline: PipelineOpts::new(NONE),
+		head: head.clone(),
+		tip: None,
+	};
+
+	info!("[op {}] Forcing head to {}.", ctx.op_id, h);
+	if *h == head.last_block_h {
+		return Ok(head);
+	}
+	let target = try!(ctx.store.tip_for_block(h).map_err(&Error::StoreErr));
+	let txs = if target.lineage_hash() != head.lineage_hash() {
+		Some(try!(reorg(&target, &mut ctx)))
+	} else {
+		None
+	};
+	let tip = try!(ctx.store.force_head(h).map_err(&Error::StoreErr));
+	ctx.head = tip.clone();
+	if let Some(txs) = txs {
+		ctx.adapter.reorged(&txs);
+	}
+	Ok(tip)
+}
+
+/// Records the total difficulty the main chain is expected to have at the
+/// provided height, see `ChainStore::add_difficulty_checkpoint`, reporting
+/// right away to the adapter the main chain blocks it makes final.
+pub fn add_difficulty_checkpoint(height: u64,
+                                 total: Difficulty,
+                                 store: Arc<ChainStore>,
+                                 adapter: Arc<ChainAdapter>)
+                                 -> Result<(), Error> {
+	try!(store.add_difficulty_checkpoint(height, total).map_err(&Error::StoreErr));
+	notify_finalized(&*store, &*adapter)
 }
 
 /// Quick in-memory check to fast-reject any block we've already handled
@@ -125,6 +523,17 @@ fn validate_header(b: &Block, ctx: &mut BlockContext) -> Result<(), Error> {
 
 	let prev = try!(ctx.store.get_block_header(&header.previous).map_err(&Error::StoreErr));
 
+	// cheap sanity check on the declared difficulty before anything else
+	let (min, max) = ctx.pipeline.bounds.range(&prev);
+	let got = Difficulty::from_target(&header.target);
+	if got < min || got > max {
+		return Err(Error::DifficultyOutOfRange {
+			got: got,
+			min: min,
+			max: max,
+		});
+	}
+
 	if header.timestamp <= prev.timestamp {
 		// prevent time warp attacks and some timestamp manipulations by forcing strict
 		// time progression
@@ -145,42 +554,72 @@ fn validate_header(b: &Block, ctx: &mut BlockContext) -> Result<(), Error> {
 	if header.target > diff_target {
 		return Err(Error::TargetTooHigh);
 	}
-	if header.cuckoo_len != cuckoo_sz && !ctx.opts.intersects(EASY_POW) {
+	if header.cuckoo_len != cuckoo_sz && !ctx.pipeline.opts.intersects(EASY_POW) {
 		return Err(Error::WrongCuckooSize);
 	}
 
-	if ctx.opts.intersects(EASY_POW) {
-		if !pow::verify_size(b, 15) {
-			return Err(Error::InvalidPow);
-		}
-	} else if !pow::verify(b) {
-		return Err(Error::InvalidPow);
-	}
+	ctx.pipeline.verifier.verify_header(b, &prev)
+}
 
+/// Finds the tip the block extends, either the head, a side chain tip or a
+/// new fork from a block inside one of the tracked forks.
+fn set_tip(h: &BlockHeader, ctx: &mut BlockContext) -> Result<(), Error> {
+	let tip = if h.previous == ctx.head.last_block_h {
+		ctx.head.clone()
+	} else {
+		let found = match ctx.store.tip_for_block(&h.previous) {
+			Err(types::Error::NotFoundErr) => ctx.store.fork_tip(&h.previous),
+			res => res,
+		};
+		match found {
+			Ok(tip) => tip,
+			Err(types::Error::NotFoundErr) => {
+				return Err(Error::Unfit("doesn't extend any known tip".to_string()));
+			}
+			Err(e) => return Err(Error::StoreErr(e)),
+		}
+	};
+	// refuse a block lying about its height or overflowing the total
+	// difficulty before any costlier validation
+	try!(tip.append(h).map_err(&Error::StoreErr));
+	ctx.tip = Some(tip);
 	Ok(())
 }
 
-fn set_tip(h: &BlockHeader, ctx: &mut BlockContext) -> Result<(), Error> {
-	// TODO actually support more than one branch
-	if h.previous != ctx.head.last_block_h {
-		return Err(Error::Unfit("Just don't know where to put it right now".to_string()));
+/// Verifies the total difficulty of the tip once extended with the block
+/// against the checkpoint at its height, if any. A mismatch means our
+/// difficulty computation diverged from the rest of the network.
+fn check_difficulty_checkpoint(h: &BlockHeader, ctx: &mut BlockContext) -> Result<(), Error> {
+	let tip = ctx.tip.as_ref().unwrap();
+	let expected = match ctx.store.get_difficulty_checkpoint(tip.height + 1) {
+		Ok(expected) => expected,
+		Err(types::Error::NotFoundErr) => return Ok(()),
+		Err(e) => return Err(Error::StoreErr(e)),
+	};
+	let total = try!(types::add_difficulty(tip.total_difficulty, h).map_err(&Error::StoreErr));
+	if total != expected {
+		return Err(Error::DifficultyCheckpointMismatch(tip.height + 1, expected, total));
 	}
-	// TODO validate block header height
-	ctx.tip = Some(ctx.head.clone());
 	Ok(())
 }
 
 fn validate_block(b: &Block, ctx: &mut BlockContext) -> Result<(), Error> {
-	// TODO check tx merkle tree
-	let curve = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
-	try!(b.verify(&curve).map_err(&Error::InvalidBlockProof));
-	Ok(())
+	ctx.pipeline.verifier.verify_body(b)
+}
+
+fn check_policy(b: &Block, ctx: &mut BlockContext) -> Result<(), Error> {
+	let parent = try!(ctx.store.get_block_header(&b.header.previous).map_err(&Error::StoreErr));
+	ctx.pipeline.policy.admit(b, &parent).map_err(&Error::PolicyRejected)
 }
 
 fn add_block(b: &Block, ctx: &mut BlockContext) -> Result<(), Error> {
 	// save the block and appends it to the selected tip
-	ctx.tip = ctx.tip.as_ref().map(|t| t.append(b.hash()));
-	ctx.store.save_block(b).map_err(&Error::StoreErr);
+	let tip = match ctx.tip {
+		Some(ref t) => Some(try!(t.append(&b.header).map_err(&Error::StoreErr))),
+		None => None,
+	};
+	ctx.tip = tip;
+	try!(ctx.store.save_block(b).map_err(&Error::StoreErr));
 
 	// broadcast the block
 	let adapter = ctx.adapter.clone();
@@ -188,7 +627,156 @@ fn add_block(b: &Block, ctx: &mut BlockContext) -> Result<(), Error> {
 	Ok(())
 }
 
-fn update_tips(ctx: &mut BlockContext) -> Result<(), Error> {
-	let tip = ctx.tip.as_ref().unwrap();
-	ctx.store.save_head(tip).map_err(&Error::StoreErr)
+/// Moves the header tip to the new head if it now has more work, the header
+/// chain being always at least as worked as the full block chain.
+fn update_header_tip(head: &Tip, ctx: &mut BlockContext) -> Result<(), Error> {
+	match ctx.store.best_header_tip() {
+		Ok(ht) => {
+			if ht.total_difficulty >= head.total_difficulty {
+				return Ok(());
+			}
+		}
+		Err(types::Error::NotFoundErr) => {}
+		Err(e) => return Err(Error::StoreErr(e)),
+	}
+	ctx.store.save_header_tip(&HeaderTip::from_tip(head)).map_err(&Error::StoreErr)
+}
+
+/// Figures out the transactions that change with the head moving to a tip on
+/// another fork, from the bodies of the blocks getting disconnected and
+/// connected. Done before the head moves, so a reorg we can't account for
+/// (deeper than a chain diff, over pruned bodies) leaves the head untouched
+/// instead of the adapter out of sync.
+fn reorg(new_head: &Tip, ctx: &mut BlockContext) -> Result<ReorgTxSet, Error> {
+	// the new head is still on a side fork, which is what a chain diff gives
+	let diff = try!(ctx.store.chain_diff(new_head).map_err(&Error::StoreErr));
+
+	// none of the blocks getting disconnected can be final
+	let fork_height = ctx.head.height - diff.missing.len() as u64;
+	let finalized = try!(ctx.store.finalized_height().map_err(&Error::StoreErr));
+	if fork_height + 1 < finalized {
+		return Err(Error::StoreErr(types::Error::ForkBelowFinalized(fork_height)));
+	}
+	let ancestor = ctx.store.common_ancestor(&ctx.head, new_head);
+	info!("[op {}] Reorg from {} at {} to {} at {}, forks diverging after branch {}, \
+	       disconnecting {} and connecting {} blocks.",
+	      ctx.op_id,
+	      ctx.head.last_block_h,
+	      ctx.head.height,
+	      new_head.last_block_h,
+	      new_head.height,
+	      ancestor,
+	      diff.missing.len(),
+	      diff.disconnect.len());
+	let mut disconnected = vec![];
+	for h in diff.missing.iter().rev() {
+		disconnected.push(try!(ctx.store.get_block(h).map_err(&Error::StoreErr)));
+	}
+	let mut connected = vec![];
+	for h in diff.disconnect.iter().rev() {
+		connected.push(try!(ctx.store.get_block(h).map_err(&Error::StoreErr)));
+	}
+	Ok(ReorgTxSet::from_blocks(&disconnected, &connected))
+}
+
+/// Checks the blocks of a tip about to become the head against the
+/// checkpoints, down to where it joins the main chain. A block only gets
+/// checked against the checkpoint at its height when accepted, so a fork
+/// accepted before a checkpoint was added could otherwise win. The blocks
+/// below the fork point are the head's as well and can't tell them apart.
+fn check_fork_checkpoints(tip: &Tip, ctx: &mut BlockContext) -> Result<(), Error> {
+	let checkpoints = try!(ctx.store.difficulty_checkpoints().map_err(&Error::StoreErr));
+	if checkpoints.iter().all(|c| c.height > tip.height) {
+		return Ok(());
+	}
+	let mut total = tip.total_difficulty;
+	let mut header = try!(ctx.store.get_block_header(&tip.last_block_h).map_err(&Error::StoreErr));
+	loop {
+		if header.height <= ctx.head.height {
+			let main = try!(ctx.store.get_header_by_height(header.height).map_err(&Error::StoreErr));
+			if main.hash() == header.hash() {
+				return Ok(());
+			}
+		}
+		if let Some(c) = checkpoints.iter().find(|c| c.height == header.height) {
+			if c.total_difficulty != total {
+				return Err(Error::CheckpointMismatch(c.height, c.total_difficulty, total));
+			}
+		}
+		if header.height == 0 {
+			return Ok(());
+		}
+		total = try!(types::sub_difficulty(total, &header).map_err(&Error::StoreErr));
+		header = try!(ctx.store.get_block_header(&header.previous).map_err(&Error::StoreErr));
+	}
+}
+
+/// Everything that has to hold before a heavier tip becomes the head, the
+/// checkpoints and the reorg being possible, returning the transactions the
+/// reorg changes if the tip is on another fork.
+fn promote_checks(tip: &Tip, ctx: &mut BlockContext) -> Result<Option<ReorgTxSet>, Error> {
+	try!(check_fork_checkpoints(tip, ctx));
+	if tip.lineage_hash() == ctx.head.lineage_hash() {
+		return Ok(None);
+	}
+	reorg(tip, ctx).map(Some)
+}
+
+/// Reports the main chain blocks that went below the finalized height since
+/// the last time to the adapter, lowest first.
+fn notify_finalized(store: &ChainStore, adapter: &ChainAdapter) -> Result<(), Error> {
+	let finalized = try!(store.finalized_height().map_err(&Error::StoreErr));
+	let notified = try!(store.finalized_notified_height().map_err(&Error::StoreErr));
+	if finalized <= notified {
+		return Ok(());
+	}
+	for height in notified..finalized {
+		let header = try!(store.get_header_by_height(height).map_err(&Error::StoreErr));
+		adapter.block_finalized(&header);
+	}
+	store.save_finalized_notified_height(finalized).map_err(&Error::StoreErr)
+}
+
+/// Saves the updated tip, promoting it to head if its fork now has more total
+/// difficulty than the head. The updated tip keeps the lineage of the one it
+/// extends so it replaces it in store. Returns the new head if it changed.
+/// A fork that can't become the head is still tracked, the error being
+/// returned after saving it.
+fn update_tips(ctx: &mut BlockContext) -> Result<Option<Tip>, Error> {
+	let tip = ctx.tip.clone().unwrap();
+	if tip.total_difficulty > ctx.head.total_difficulty {
+		let txs = match promote_checks(&tip, ctx) {
+			Ok(txs) => txs,
+			Err(e) => {
+				try!(ctx.store.save_tip(&tip).map_err(&Error::StoreErr));
+				return Err(e);
+			}
+		};
+		try!(ctx.store.save_head(&tip).map_err(&Error::StoreErr));
+		debug!("[op {}] New head {} at {}.",
+		       ctx.op_id,
+		       tip.last_block_h,
+		       tip.height);
+		ctx.head = tip.clone();
+		if let Some(txs) = txs {
+			ctx.adapter.reorged(&txs);
+		}
+
+		// the head moved already, the block is accepted whatever happens next
+		if let Err(e) = update_header_tip(&tip, ctx) {
+			warn!("[op {}] Could not update the header tip: {:?}", ctx.op_id, e);
+		}
+		if let Err(e) = notify_finalized(&*ctx.store, &*ctx.adapter) {
+			warn!("[op {}] Could not notify finalized blocks: {:?}", ctx.op_id, e);
+		}
+		Ok(Some(tip))
+	} else {
+		try!(ctx.store.save_tip(&tip).map_err(&Error::StoreErr));
+		debug!("[op {}] Side chain {} extended to {} at {}.",
+		       ctx.op_id,
+		       tip.lineage.last_branch(),
+		       tip.last_block_h,
+		       tip.height);
+		Ok(None)
+	}
 }
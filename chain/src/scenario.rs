@@ -0,0 +1,317 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Scripted chains of blocks and forks driven through the pipeline, to test
+//! fork choice and reorgs in a few lines. Only available with the testing
+//! feature.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use time;
+
+use core::consensus::MAX_TARGET;
+use core::core::hash::Hash;
+use core::core::{Block, BlockHeader};
+use core::genesis;
+use pipe::{self, PipelineOpts, Verifier};
+use store::{ChainKVStore, TempDir};
+use types::{ChainStore, NoopAdapter, Tip};
+
+// Skips the proof of work and signatures, scenario blocks have neither
+struct TrustingVerifier {}
+
+impl Verifier for TrustingVerifier {
+	fn verify_header(&self, _: &Block, _: &BlockHeader) -> Result<(), pipe::Error> {
+		Ok(())
+	}
+
+	fn verify_body(&self, _: &Block) -> Result<(), pipe::Error> {
+		Ok(())
+	}
+}
+
+/// A chain built block by block from a script, each block naming its parent
+/// and its difficulty. Blocks go through the full pipeline, forks included,
+/// only their proof of work and signatures aren't checked.
+///
+/// Difficulties are powers of two. As with the consensus retarget, a block
+/// can't be easier than its parent.
+pub struct ChainScenario {
+	store: Arc<ChainKVStore>,
+	blocks: HashMap<Hash, Block>,
+	genesis: Hash,
+	nonce: u64,
+	// last so the store goes first
+	_dir: TempDir,
+}
+
+impl ChainScenario {
+	/// New scenario with just the genesis block, in a fresh store named after
+	/// the scenario and removed with it.
+	pub fn new(name: &str) -> ChainScenario {
+		let dir = TempDir::new(&format!("grin_scenario_{}", name));
+		let store = ChainKVStore::new(dir.path()).unwrap();
+		let gen = genesis::genesis();
+		store.init(&gen).unwrap();
+		let mut blocks = HashMap::new();
+		let genesis = gen.hash();
+		blocks.insert(genesis, gen);
+		ChainScenario {
+			store: Arc::new(store),
+			blocks: blocks,
+			genesis: genesis,
+			nonce: 0,
+			_dir: dir,
+		}
+	}
+
+	/// Hash of the genesis block.
+	pub fn genesis(&self) -> Hash {
+		self.genesis
+	}
+
+	/// The store the scenario runs against.
+	pub fn store(&self) -> Arc<ChainKVStore> {
+		self.store.clone()
+	}
+
+	/// Adds a block with the provided difficulty on top of the parent, the
+	/// pipeline starting a new fork if the parent isn't the last block of a
+	/// tip. Panics if the pipeline refuses the block. Returns the block hash.
+	pub fn block(&mut self, parent: Hash, difficulty: u64) -> Hash {
+		let (h, res) = self.try_block(parent, difficulty);
+		if let Err(e) = res {
+			panic!("block {} on {} refused: {:?}", h, parent, e);
+		}
+		h
+	}
+
+	/// Same as block but returns the block hash along with the pipeline
+	/// result instead of panicking when refused.
+	pub fn try_block(&mut self,
+	                 parent: Hash,
+	                 difficulty: u64)
+	                 -> (Hash, Result<Option<Tip>, pipe::Error>) {
+		assert!(difficulty.is_power_of_two(),
+		        "scenario difficulties are powers of two");
+		let b = {
+			let prev = &self.blocks[&parent];
+			let mut b = genesis::genesis();
+			b.header.height = prev.header.height + 1;
+			b.header.previous = parent;
+			b.header.timestamp = prev.header.timestamp + time::Duration::seconds(60);
+			b.header.target = MAX_TARGET >> (difficulty.trailing_zeros() as usize);
+			b.header.nonce = self.nonce;
+			b
+		};
+		self.nonce += 1;
+
+		let pipeline = PipelineOpts::new(pipe::EASY_POW).with_verifier(Arc::new(TrustingVerifier {}));
+		let res = pipe::process_block(&b, self.store.clone(), Arc::new(NoopAdapter {}), &pipeline);
+		let h = b.hash();
+		self.blocks.insert(h, b);
+		(h, res)
+	}
+
+	/// Adds a run of blocks, each on top of the previous one starting from
+	/// the parent, with the provided difficulties. Returns their hashes.
+	pub fn blocks(&mut self, parent: Hash, difficulties: &[u64]) -> Vec<Hash> {
+		let mut hashes = vec![];
+		let mut prev = parent;
+		for &difficulty in difficulties {
+			prev = self.block(prev, difficulty);
+			hashes.push(prev);
+		}
+		hashes
+	}
+
+	/// Asserts the head is the provided block and the chain is consistent.
+	pub fn assert_head(&self, h: Hash) {
+		let head = self.store.head().unwrap();
+		assert_eq!(head.last_block_h, h, "unexpected head at {}", head.height);
+		self.store.verify_chain(0).unwrap();
+	}
+
+	/// Asserts the number of tips tracked, the head's included.
+	pub fn assert_tip_count(&self, n: usize) {
+		assert_eq!(self.store.tracked_fork_count().unwrap(), n);
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use types::MAX_CHAIN_DIFF;
+
+	#[test]
+	fn overtake_from_common_ancestor() {
+		let mut s = ChainScenario::new("ancestor");
+		let gen = s.genesis();
+		let main = s.blocks(gen, &[1, 1, 1]);
+		let fork = s.blocks(main[0], &[1, 1]);
+		// as much work as the head isn't enough
+		s.assert_head(main[2]);
+		s.assert_tip_count(2);
+		let fork_b4 = s.block(fork[1], 1);
+		s.assert_head(fork_b4);
+		s.assert_tip_count(2);
+	}
+
+	#[test]
+	fn keep_first_seen_on_tie() {
+		let mut s = ChainScenario::new("tie");
+		let gen = s.genesis();
+		let a = s.blocks(gen, &[1, 1, 2]);
+		let b = s.blocks(a[0], &[1, 2]);
+		assert!(a[2] != b[1]);
+		s.assert_head(a[2]);
+		s.assert_tip_count(2);
+	}
+
+	#[test]
+	fn more_work_in_fewer_blocks() {
+		let mut s = ChainScenario::new("heavier");
+		let gen = s.genesis();
+		let main = s.blocks(gen, &[1, 1, 1, 1, 1]);
+		let fork_b2 = s.block(main[0], 4);
+		s.assert_head(main[4]);
+		let fork_b3 = s.block(fork_b2, 4);
+		s.assert_head(fork_b3);
+		assert_eq!(s.store().head().unwrap().height, 3);
+	}
+
+	#[test]
+	fn deep_reorg() {
+		let mut s = ChainScenario::new("deep");
+		let gen = s.genesis();
+		let main = s.blocks(gen, &[1; 10]);
+		let fork = s.blocks(main[0], &[1; 10]);
+		s.assert_head(fork[9]);
+		assert!(s.store().get_stale_blocks(0).unwrap().contains(&main[9]));
+	}
+
+	#[test]
+	fn reorg_over_pruned_bodies() {
+		let mut s = ChainScenario::new("pruned");
+		let gen = s.genesis();
+		let main = s.blocks(gen, &[1, 1, 1]);
+		let fork = s.blocks(main[0], &[1, 1]);
+		s.store().prune_bodies(3).unwrap();
+
+		// the disconnected main[1] body is gone, the head can't move
+		match s.try_block(fork[1], 1).1 {
+			Err(pipe::Error::StoreErr(::types::Error::BlockPruned(2))) => {}
+			res => panic!("reorg over a pruned body went through: {:?}", res),
+		}
+		s.assert_head(main[2]);
+		s.assert_tip_count(2);
+		let tips = s.store().get_tips().unwrap();
+		assert!(tips.iter().any(|t| t.height == 4));
+	}
+
+	#[test]
+	fn no_reorg_below_finalized() {
+		let mut s = ChainScenario::new("finalized");
+		let gen = s.genesis();
+		let main = s.blocks(gen, &[1, 1, 1]);
+		let fork = s.blocks(main[0], &[1, 1]);
+		// both forks have the checkpointed total at height 3, only finality
+		// keeps the fork out
+		let total = s.store().head().unwrap().total_difficulty;
+		s.store().add_difficulty_checkpoint(3, total).unwrap();
+		assert_eq!(s.store().finalized_height().unwrap(), 4);
+
+		match s.try_block(fork[1], 1).1 {
+			Err(pipe::Error::StoreErr(::types::Error::ForkBelowFinalized(1))) => {}
+			res => panic!("reorged below the finalized height: {:?}", res),
+		}
+		s.assert_head(main[2]);
+		s.assert_tip_count(2);
+	}
+
+	#[test]
+	fn cache_reorg_ancestors() {
+		let mut s = ChainScenario::new("ancestors");
+		let gen = s.genesis();
+		let main = s.blocks(gen, &[1, 1]);
+		let fork = s.blocks(main[0], &[1, 1]);
+		s.assert_head(fork[1]);
+		assert_eq!(s.store().ancestor_cache_stats().misses, 1);
+
+		// reorging back between the same forks doesn't walk the lineages again
+		let back = s.blocks(main[1], &[1, 1]);
+		s.assert_head(back[1]);
+		let stats = s.store().ancestor_cache_stats();
+		assert_eq!((stats.hits, stats.misses), (1, 1));
+	}
+
+	#[test]
+	fn chain_diff_bound() {
+		let mut s = ChainScenario::new("diff_bound");
+		let gen = s.genesis();
+		let peer = Tip::genesis(&genesis::genesis());
+		let main = s.blocks(gen, &[1; MAX_CHAIN_DIFF]);
+		assert_eq!(s.store().chain_diff(&peer).unwrap().missing.len(), MAX_CHAIN_DIFF);
+		s.block(main[MAX_CHAIN_DIFF - 1], 1);
+		match s.store().chain_diff(&peer) {
+			Err(::types::Error::DiffTooLarge) => {}
+			res => panic!("diff over the bound: {:?}", res),
+		}
+	}
+
+	#[test]
+	fn chain_diff_disconnect_bound() {
+		let mut s = ChainScenario::new("disconnect_bound");
+		let gen = s.genesis();
+		s.blocks(gen, &[2; MAX_CHAIN_DIFF]);
+		// the peer is on a lighter fork as long as our main chain
+		let fork = s.blocks(gen, &[1; MAX_CHAIN_DIFF]);
+		let mut peer = s.store().tip_for_block(&fork[MAX_CHAIN_DIFF - 1]).unwrap();
+		let diff = s.store().chain_diff(&peer).unwrap();
+		assert_eq!(diff.missing.len(), MAX_CHAIN_DIFF);
+		assert_eq!(diff.disconnect.len(), MAX_CHAIN_DIFF);
+		let last = s.block(fork[MAX_CHAIN_DIFF - 1], 1);
+		peer = s.store().tip_for_block(&last).unwrap();
+		match s.store().chain_diff(&peer) {
+			Err(::types::Error::DiffTooLarge) => {}
+			res => panic!("diff over the bound: {:?}", res),
+		}
+	}
+
+	#[test]
+	fn fork_from_genesis() {
+		let mut s = ChainScenario::new("from_genesis");
+		let gen = s.genesis();
+		let main = s.blocks(gen, &[1, 1]);
+		let fork = s.blocks(gen, &[1, 1, 1]);
+		s.assert_head(fork[2]);
+		assert!(s.store().get_stale_blocks(0).unwrap().contains(&main[1]));
+		s.assert_tip_count(2);
+	}
+
+	#[test]
+	fn fork_of_fork() {
+		let mut s = ChainScenario::new("nested");
+		let gen = s.genesis();
+		let main = s.blocks(gen, &[1, 1, 1]);
+		let fork = s.blocks(main[0], &[1, 1, 1]);
+		s.assert_head(fork[2]);
+		let nested = s.blocks(fork[0], &[1, 1, 1]);
+		s.assert_head(nested[2]);
+		s.assert_tip_count(3);
+		let head = s.store().head().unwrap();
+		assert_eq!(head.lineage.depth(), 3);
+	}
+}
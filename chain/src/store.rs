@@ -14,11 +14,27 @@
 
 //! Implements storage primitives required by the chain
 
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+
 use byteorder::{WriteBytesExt, BigEndian};
+use time;
 
+use ancestry::AncestorCache;
+use bloom::{self, SeenFilter};
+use heights::{CacheStats, HeightCache};
 use types::*;
-use core::core::hash::Hash;
+use core::consensus;
+use core::core::hash::{Hash, Hashed};
 use core::core::{Block, BlockHeader};
+use core::core::target::{Difficulty, Target};
+use core::ser;
 use grin_store;
 
 const STORE_SUBPATH: &'static str = "chain";
@@ -29,56 +45,1620 @@ const BLOCK_HEADER_PREFIX: u8 = 'h' as u8;
 const BLOCK_PREFIX: u8 = 'b' as u8;
 const TIP_PREFIX: u8 = 'T' as u8;
 const HEAD_PREFIX: u8 = 'H' as u8;
+const HEIGHT_PREFIX: u8 = 'i' as u8;
+const HEADER_HEAD_PREFIX: u8 = 'I' as u8;
+const ORPHAN_PREFIX: u8 = 'o' as u8;
+const EVENT_PREFIX: u8 = 'e' as u8;
+const EVENT_SEQ_PREFIX: u8 = 'E' as u8;
+const BODY_HORIZON_PREFIX: u8 = 'P' as u8;
+const DIFF_CHECKPOINT_PREFIX: u8 = 'D' as u8;
+const STALE_PREFIX: u8 = 's' as u8;
+const CHAIN_PREFIX: u8 = 'C' as u8;
+const FINALIZED_NOTIFIED_PREFIX: u8 = 'F' as u8;
+
+// Prefixes of the chain state entries, see ChainStore::state_snapshot
+const STATE_PREFIXES: [u8; 8] = [HEAD_PREFIX,
+                                 HEADER_HEAD_PREFIX,
+                                 TIP_PREFIX,
+                                 HEIGHT_PREFIX,
+                                 STALE_PREFIX,
+                                 EVENT_PREFIX,
+                                 EVENT_SEQ_PREFIX,
+                                 BODY_HORIZON_PREFIX];
+
+// Number of head updates buffered for a subscriber before we start dropping
+// them
+const SUBSCRIBER_BUFFER: usize = 16;
 
 /// An implementation of the ChainStore trait backed by a simple key-value
 /// store.
 pub struct ChainKVStore {
-	db: grin_store::Store,
+	db: ChainDb,
+	retry: RetryPolicy,
+	subscribers: Mutex<Vec<SyncSender<Tip>>>,
+	buffer: Option<BufferPolicy>,
+	pending: Mutex<PendingWrites>,
+	max_tracked_forks: Option<usize>,
+	seen: Option<Mutex<SeenFilter>>,
+	heights: HeightCache,
+	ancestors: AncestorCache,
+	index_fallback_warned: AtomicBool,
+	#[cfg(feature = "testing")]
+	frozen_at: Mutex<Option<u64>>,
+}
+
+/// How many blocks, and for how long, the store buffers in memory before
+/// writing them to the db in a single batch. The buffer is flushed as well
+/// before any tip moves, so a tip never points to a block that didn't make
+/// it to the db.
+#[derive(Debug, Clone, Copy)]
+pub struct BufferPolicy {
+	/// Number of blocks buffered before a flush
+	pub max_blocks: usize,
+	/// Time after which the buffered blocks are flushed with the next write,
+	/// in milliseconds
+	pub max_delay_ms: u64,
+}
+
+/// Sizing of the filter of seen blocks.
+#[derive(Debug, Clone, Copy)]
+pub struct SeenFilterPolicy {
+	/// Number of blocks the filter is sized for
+	pub expected_blocks: usize,
+	/// Rate of false positives with the expected number of blocks
+	pub false_positive_rate: f64,
+}
+
+impl Default for SeenFilterPolicy {
+	fn default() -> SeenFilterPolicy {
+		SeenFilterPolicy {
+			expected_blocks: bloom::DEFAULT_EXPECTED_BLOCKS,
+			false_positive_rate: bloom::DEFAULT_FALSE_POSITIVE_RATE,
+		}
+	}
+}
+
+// Serialized blocks and headers not written to the db yet
+#[derive(Default)]
+struct PendingWrites {
+	entries: HashMap<Vec<u8>, Vec<u8>>,
+	blocks: usize,
+	since: Option<Instant>,
+}
+
+// The db shared by all the chains in the store, with the keys of this chain
+// prefixed by its namespace. The default chain has no namespace, so stores
+// from before multiple chains keep working.
+struct ChainDb {
+	db: Arc<grin_store::Store>,
+	ns: Vec<u8>,
+}
+
+impl ChainDb {
+	fn key(&self, k: &[u8]) -> Vec<u8> {
+		let mut nk = self.ns.clone();
+		nk.extend_from_slice(k);
+		nk
+	}
+
+	fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, grin_store::Error> {
+		self.db.get(&self.key(key))
+	}
+
+	fn get_ser<T: ser::Readable<T>>(&self, key: &[u8]) -> Result<Option<T>, grin_store::Error> {
+		self.db.get_ser(&self.key(key))
+	}
+
+	fn get_ser_limited<T: ser::Readable<T>>(&self,
+	                                        key: &[u8],
+	                                        len: usize)
+	                                        -> Result<Option<T>, grin_store::Error> {
+		self.db.get_ser_limited(&self.key(key), len)
+	}
+
+	fn get_ser_buf<T: ser::Readable<T>>(&self,
+	                                    key: &[u8],
+	                                    buf: &mut Vec<u8>)
+	                                    -> Result<Option<T>, grin_store::Error> {
+		self.db.get_ser_buf(&self.key(key), buf)
+	}
+
+	fn get_ser_prefix<T: ser::Readable<T>>(&self,
+	                                       prefix: &[u8])
+	                                       -> Result<Vec<T>, grin_store::Error> {
+		self.db.get_ser_prefix(&self.key(prefix))
+	}
+
+	fn get_ser_from<T: ser::Readable<T>>(&self,
+	                                     prefix: &[u8],
+	                                     from: &[u8])
+	                                     -> Result<Vec<T>, grin_store::Error> {
+		self.db.get_ser_from(&self.key(prefix), &self.key(from))
+	}
+
+	fn for_each_key<F>(&self, prefix: &[u8], mut f: F)
+		where F: FnMut(&[u8])
+	{
+		let ns_len = self.ns.len();
+		self.db.for_each_key(&self.key(prefix), |k| f(&k[ns_len..]))
+	}
+
+	fn put(&self, key: &[u8], value: Vec<u8>) -> Result<(), grin_store::Error> {
+		self.db.put(&self.key(key), value)
+	}
+
+	fn put_ser(&self, key: &[u8], value: &ser::Writeable) -> Result<(), grin_store::Error> {
+		self.db.put_ser(&self.key(key), value)
+	}
+
+	fn put_batch(&self, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<(), grin_store::Error> {
+		let entries: Vec<(Vec<u8>, Vec<u8>)> =
+			entries.iter().map(|&(ref k, ref v)| (self.key(k), v.clone())).collect();
+		self.db.put_batch(&entries)
+	}
+
+	fn write_batch(&self,
+	               deletes: &[Vec<u8>],
+	               entries: &[(Vec<u8>, Vec<u8>)])
+	               -> Result<(), grin_store::Error> {
+		let deletes: Vec<Vec<u8>> = deletes.iter().map(|k| self.key(k)).collect();
+		let entries: Vec<(Vec<u8>, Vec<u8>)> =
+			entries.iter().map(|&(ref k, ref v)| (self.key(k), v.clone())).collect();
+		self.db.write_batch(&deletes, &entries)
+	}
+
+	fn delete(&self, key: &[u8]) -> Result<(), grin_store::Error> {
+		self.db.delete(&self.key(key))
+	}
+}
+
+/// How the store retries writes failing with a transient error.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+	/// Number of retries after the first failed attempt
+	pub max_retries: u32,
+	/// Delay before the first retry, in milliseconds, doubling for each
+	/// following retry
+	pub base_delay_ms: u64,
+}
+
+impl RetryPolicy {
+	/// Policy never retrying, failing on the first error.
+	pub fn none() -> RetryPolicy {
+		RetryPolicy {
+			max_retries: 0,
+			base_delay_ms: 0,
+		}
+	}
+}
+
+impl Default for RetryPolicy {
+	fn default() -> RetryPolicy {
+		RetryPolicy {
+			max_retries: 3,
+			base_delay_ms: 10,
+		}
+	}
 }
 
 impl ChainKVStore {
 	pub fn new(root_path: String) -> Result<ChainKVStore, Error> {
 		let db = try!(grin_store::Store::open(format!("{}/{}", root_path, STORE_SUBPATH).as_str())
 			.map_err(to_store_err));
-		Ok(ChainKVStore { db: db })
+		let db = ChainDb {
+			db: Arc::new(db),
+			ns: vec![],
+		};
+		Ok(ChainKVStore::with_db(db, RetryPolicy::default(), None))
+	}
+
+	/// Another chain hosted in the same underlying db, fully isolated from
+	/// this one and all the others. Needs to be initialized like any new
+	/// chain.
+	pub fn chain(&self, id: ChainId) -> ChainKVStore {
+		let mut ns = vec![CHAIN_PREFIX, SEP];
+		ns.write_u32::<BigEndian>(id.0).unwrap();
+		ns.push(SEP);
+		let db = ChainDb {
+			db: self.db.db.clone(),
+			ns: ns,
+		};
+		let mut store = ChainKVStore::with_db(db, self.retry, self.buffer);
+		store.max_tracked_forks = self.max_tracked_forks;
+		store
+	}
+
+	fn with_db(db: ChainDb, retry: RetryPolicy, buffer: Option<BufferPolicy>) -> ChainKVStore {
+		ChainKVStore {
+			db: db,
+			retry: retry,
+			subscribers: Mutex::new(vec![]),
+			buffer: buffer,
+			pending: Mutex::new(PendingWrites::default()),
+			max_tracked_forks: None,
+			seen: None,
+			heights: HeightCache::default(),
+			ancestors: AncestorCache::default(),
+			index_fallback_warned: AtomicBool::new(false),
+			#[cfg(feature = "testing")]
+			frozen_at: Mutex::new(None),
+		}
+	}
+
+	/// Freezes the chain at the provided height, refusing to connect any
+	/// block above it until unfrozen. Reads aren't affected.
+	#[cfg(feature = "testing")]
+	pub fn freeze(&self, height: u64) {
+		*self.frozen_at.lock().unwrap() = Some(height);
+	}
+
+	/// Lets the chain advance again after a freeze.
+	#[cfg(feature = "testing")]
+	pub fn unfreeze(&self) {
+		*self.frozen_at.lock().unwrap() = None;
+	}
+
+	/// Height the chain is frozen at, if any.
+	#[cfg(feature = "testing")]
+	pub fn frozen_at(&self) -> Option<u64> {
+		*self.frozen_at.lock().unwrap()
+	}
+
+	/// Writes a snapshot of the chain metadata (head, tips, checkpoints,
+	/// horizon) to the provided writer, leaving the block data out.
+	pub fn export_metadata<W: Write>(&self, mut w: W) -> Result<(), Error> {
+		let meta = ChainMetadata {
+			head: try!(self.head()),
+			tips: try!(self.get_tips()),
+			header_tip: try!(self.best_header_tip()),
+			difficulty_checkpoints: try!(self.difficulty_checkpoints()),
+			body_horizon: try!(self.body_horizon()),
+		};
+		ser::serialize(&mut w, &meta).map_err(|e| to_store_err(grin_store::Error::SerErr(e)))
+	}
+
+	/// Restores a metadata snapshot from the provided reader, replacing the
+	/// tips, the difficulty checkpoints and the head. The store must already
+	/// hold the matching block data, the height index being rebuilt from it.
+	pub fn import_metadata<R: Read>(&self, mut r: R) -> Result<(), Error> {
+		let meta: ChainMetadata = try!(ser::deserialize(&mut r)
+			.map_err(|e| to_store_err(grin_store::Error::SerErr(e))));
+		for t in try!(self.get_tips()) {
+			try!(self.delete_tip(&t));
+		}
+		for t in &meta.tips {
+			try!(self.save_tip(t));
+		}
+		// the checkpoints we had are replaced at once by the imported ones
+		let mut stale = vec![];
+		self.db.for_each_key(&[DIFF_CHECKPOINT_PREFIX, SEP], |k| stale.push(k.to_vec()));
+		let mut checkpoints = vec![];
+		for c in &meta.difficulty_checkpoints {
+			let data = try!(ser::ser_vec(c)
+				.map_err(|e| to_store_err(grin_store::Error::SerErr(e))));
+			checkpoints.push((diff_checkpoint_key(c.height), data));
+		}
+		try!(self.with_retry(|| self.db.write_batch(&stale, &checkpoints)));
+		try!(self.put_ser(&vec![BODY_HORIZON_PREFIX], &StoredU64(meta.body_horizon)));
+		try!(self.save_header_tip(&meta.header_tip));
+		self.save_head(&meta.head)
+	}
+
+	/// Replaces the policy used to retry failed writes.
+	pub fn with_retry_policy(mut self, policy: RetryPolicy) -> ChainKVStore {
+		self.retry = policy;
+		self
+	}
+
+	/// Buffers the blocks saved with the provided policy instead of writing
+	/// them right away, for faster bulk writes during sync.
+	pub fn with_write_buffer(mut self, policy: BufferPolicy) -> ChainKVStore {
+		self.buffer = Some(policy);
+		self
+	}
+
+	/// Soft limit on the number of tips tracked. When over it, the lightest
+	/// tips below the finalized height are dropped with the blocks they don't
+	/// share with the other tips, they can't become the head anymore anyway.
+	/// The head, and tips that could still overtake it, are always kept.
+	pub fn with_max_tracked_forks(mut self, max: usize) -> ChainKVStore {
+		self.max_tracked_forks = Some(max);
+		self
+	}
+
+	/// Keeps an in-memory filter of the blocks seen by this chain, for
+	/// probably_have to answer, sized with the provided policy. The filter is
+	/// built from the keys of all the block headers we have, one at a time.
+	pub fn with_seen_filter(mut self, policy: SeenFilterPolicy) -> Result<ChainKVStore, Error> {
+		let mut filter = SeenFilter::new(policy.expected_blocks, policy.false_positive_rate);
+		let prefix = vec![BLOCK_HEADER_PREFIX, SEP];
+		let mut malformed = 0;
+		self.db.for_each_key(&prefix, |k| {
+			let h = &k[prefix.len()..];
+			if h.len() != 32 {
+				malformed += 1;
+				return;
+			}
+			let mut hash = [0; 32];
+			hash.copy_from_slice(h);
+			filter.insert(&Hash(hash));
+		});
+		if malformed > 0 {
+			let reason = format!("{} malformed block header keys", malformed);
+			return Err(Error::StorageErr(StorageError::Corruption(reason)));
+		}
+		self.seen = Some(Mutex::new(filter));
+		Ok(self)
+	}
+
+	/// Caches the main chain height of up to the provided number of block
+	/// hashes instead of the default, zero disabling the cache.
+	pub fn with_height_cache(mut self, capacity: usize) -> ChainKVStore {
+		self.heights = HeightCache::new(capacity);
+		self
+	}
+
+	/// Caches the common ancestor of up to the provided number of lineage
+	/// pairs instead of the default, zero disabling the cache.
+	pub fn with_ancestor_cache(mut self, capacity: usize) -> ChainKVStore {
+		self.ancestors = AncestorCache::new(capacity);
+		self
+	}
+
+	// Writes a block or header, buffering it if configured to, flushing the
+	// buffer when full or old enough
+	fn put_block_data(&self, entries: Vec<(Vec<u8>, Vec<u8>)>) -> Result<(), Error> {
+		let policy = match self.buffer {
+			Some(policy) => policy,
+			None => {
+				for (key, data) in entries {
+					try!(self.put(&key[..], data));
+				}
+				return Ok(());
+			}
+		};
+		let mut pending = self.pending.lock().unwrap();
+		pending.entries.extend(entries);
+		pending.blocks += 1;
+		let since = *pending.since.get_or_insert(Instant::now());
+		if pending.blocks >= policy.max_blocks ||
+		   since.elapsed() >= Duration::from_millis(policy.max_delay_ms) {
+			try!(self.flush_pending(&mut pending));
+		}
+		Ok(())
+	}
+
+	fn flush_pending(&self, pending: &mut PendingWrites) -> Result<(), Error> {
+		if pending.entries.is_empty() {
+			return Ok(());
+		}
+		let entries: Vec<(Vec<u8>, Vec<u8>)> = pending.entries.drain().collect();
+		try!(self.with_retry(|| self.db.put_batch(&entries)));
+		debug!("Flushed {} buffered blocks.", pending.blocks);
+		pending.blocks = 0;
+		pending.since = None;
+		Ok(())
+	}
+
+	// Same as the db get_ser, looking in the write buffer first
+	fn get_ser_buffered<T: ser::Readable<T>>(&self,
+	                                         key: &[u8])
+	                                         -> Result<Option<T>, grin_store::Error> {
+		if let Some(data) = self.pending.lock().unwrap().entries.get(key) {
+			return ser::deserialize(&mut &data[..]).map(Some).map_err(grin_store::Error::SerErr);
+		}
+		self.db.get_ser(key)
+	}
+
+	// Same as get_ser_buffered, reading from the db through the provided
+	// scratch buffer
+	fn get_ser_buffered_buf<T: ser::Readable<T>>(&self,
+	                                             key: &[u8],
+	                                             buf: &mut Vec<u8>)
+	                                             -> Result<Option<T>, grin_store::Error> {
+		if let Some(data) = self.pending.lock().unwrap().entries.get(key) {
+			return ser::deserialize(&mut &data[..]).map(Some).map_err(grin_store::Error::SerErr);
+		}
+		self.db.get_ser_buf(key, buf)
+	}
+
+	fn put_ser(&self, key: &[u8], value: &ser::Writeable) -> Result<(), Error> {
+		self.with_retry(|| self.db.put_ser(key, value))
+	}
+
+	fn put(&self, key: &[u8], value: Vec<u8>) -> Result<(), Error> {
+		self.with_retry(|| self.db.put(key, value.clone()))
+	}
+
+	fn delete(&self, key: &[u8]) -> Result<(), Error> {
+		self.pending.lock().unwrap().entries.remove(key);
+		self.with_retry(|| self.db.delete(key))
+	}
+
+	// Runs a write, retrying it with exponential backoff while it fails with a
+	// transient error and the retry policy allows it.
+	fn with_retry<F>(&self, write: F) -> Result<(), Error>
+		where F: Fn() -> Result<(), grin_store::Error>
+	{
+		let mut delay = self.retry.base_delay_ms;
+		let mut retries = 0;
+		loop {
+			match write().map_err(&to_store_err) {
+				Err(Error::StorageErr(ref e)) if e.is_transient() &&
+				                                 retries < self.retry.max_retries => {
+					warn!("Transient store error, retrying in {}ms: {:?}", delay, e);
+					thread::sleep(Duration::from_millis(delay));
+					delay *= 2;
+					retries += 1;
+				}
+				res => return res,
+			}
+		}
+	}
+
+	// Sends the new head to all subscribers, dropping the notification for
+	// the ones that are lagging behind and forgetting the ones that are gone.
+	fn notify_head(&self, t: &Tip) {
+		let mut subscribers = self.subscribers.lock().unwrap();
+		subscribers.retain(|s| match s.try_send(t.clone()) {
+			Ok(()) => true,
+			Err(TrySendError::Full(_)) => {
+				debug!("Head subscriber lagging, dropping head {}.", t.last_block_h);
+				true
+			}
+			Err(TrySendError::Disconnected(_)) => false,
+		});
 	}
 }
 
 impl ChainStore for ChainKVStore {
+	fn init(&self, gen: &Block) -> Result<Tip, Error> {
+		try!(self.save_block(gen));
+		let tip = Tip::genesis(gen);
+		try!(self.save_head(&tip));
+		try!(self.save_header_tip(&HeaderTip::from_tip(&tip)));
+		Ok(tip)
+	}
+
 	fn head(&self) -> Result<Tip, Error> {
-		option_to_not_found(self.db.get_ser(&vec![HEAD_PREFIX]))
+		tip_or_not_found(self.db.get_ser(&vec![HEAD_PREFIX]))
+	}
+
+	fn total_work(&self) -> Result<Difficulty, Error> {
+		self.head().map(|head| head.total_difficulty)
+	}
+
+	fn head_height(&self) -> Result<u64, Error> {
+		// the height comes first in a serialized tip, skips reading the lineage
+		let h: TipHeight = try!(option_to_not_found(self.db.get_ser_limited(&vec![HEAD_PREFIX], 8)));
+		Ok(h.0)
 	}
 
 	fn head_header(&self) -> Result<BlockHeader, Error> {
-		let head: Tip = try!(option_to_not_found(self.db.get_ser(&vec![HEAD_PREFIX])));
+		let head: Tip = try!(tip_or_not_found(self.db.get_ser(&vec![HEAD_PREFIX])));
 		self.get_block_header(&head.last_block_h)
 	}
 
 	fn save_block(&self, b: &Block) -> Result<(), Error> {
-		try!(self.db
-			.put_ser(&to_key(BLOCK_PREFIX, &mut b.hash().to_vec())[..], b)
-			.map_err(&to_store_err));
-		self.db
-			.put_ser(&to_key(BLOCK_HEADER_PREFIX, &mut b.hash().to_vec())[..],
-			         &b.header)
-			.map_err(&to_store_err)
+		let mut hash_vec = b.hash().to_vec();
+		let key = to_key(BLOCK_PREFIX, &mut hash_vec);
+		let data = try!(ser::ser_vec(b).map_err(|e| to_store_err(grin_store::Error::SerErr(e))));
+		// never silently overwrite a stored block, a different body under the
+		// same hash is a bug somewhere (most likely in serialization)
+		if let Some(existing) = try!(self.get_raw(&key[..])) {
+			if existing != data {
+				return Err(Error::HashCollision(b.hash()));
+			}
+		}
+		let header_key = to_key(BLOCK_HEADER_PREFIX, &mut b.hash().to_vec()).clone();
+		let header_data = try!(ser::ser_vec(&b.header)
+			.map_err(|e| to_store_err(grin_store::Error::SerErr(e))));
+		try!(self.put_block_data(vec![(key.clone(), data), (header_key, header_data)]));
+		if let Some(ref seen) = self.seen {
+			seen.lock().unwrap().insert(&b.hash());
+		}
+		Ok(())
+	}
+
+	fn save_block_header(&self, bh: &BlockHeader) -> Result<(), Error> {
+		let key = to_key(BLOCK_HEADER_PREFIX, &mut bh.hash().to_vec()).clone();
+		let data = try!(ser::ser_vec(bh).map_err(|e| to_store_err(grin_store::Error::SerErr(e))));
+		self.put_block_data(vec![(key, data)])
+	}
+
+	fn flush(&self) -> Result<(), Error> {
+		let mut pending = self.pending.lock().unwrap();
+		self.flush_pending(&mut pending)
+	}
+
+	fn head_block(&self) -> Result<Block, Error> {
+		let head = try!(self.head());
+		match self.get_block(&head.last_block_h) {
+			Err(Error::NotFoundErr) => Err(Error::HeadBodyMissing(head.last_block_h)),
+			res => res,
+		}
+	}
+
+	fn repair_head(&self) -> Result<Tip, Error> {
+		let head = try!(self.head());
+		let mut tip = head.clone();
+		while !try!(self.has_block(&tip.last_block_h)) {
+			if tip.height == 0 {
+				return Err(Error::HeadBodyMissing(tip.last_block_h));
+			}
+			let header = try!(self.get_block_header(&tip.last_block_h));
+			let prev = try!(self.get_block_header(&header.previous));
+			tip = Tip {
+				height: tip.height - 1,
+				last_block_h: header.previous,
+				// same as Tip::genesis, the genesis previous isn't a block
+				prev_block_h: if prev.height == 0 { header.previous } else { prev.previous },
+				total_difficulty: try!(sub_difficulty(tip.total_difficulty, &header)),
+				lineage: tip.lineage,
+			};
+		}
+		if tip.last_block_h != head.last_block_h {
+			warn!("Head body {} missing, resetting head to {} at height {}.",
+			      head.last_block_h,
+			      tip.last_block_h,
+			      tip.height);
+			try!(self.save_head(&tip));
+		}
+		Ok(tip)
+	}
+
+	fn next_target(&self, tip: &Tip, ts: i64) -> Result<(Target, u8), Error> {
+		let last = try!(self.get_block_header(&tip.last_block_h));
+		Ok(consensus::next_target(ts,
+		                          last.timestamp.to_timespec().sec,
+		                          last.target,
+		                          last.cuckoo_len))
+	}
+
+	fn min_next_timestamp(&self, tip: &Tip) -> Result<u64, Error> {
+		let mut stamps = vec![];
+		for bh in HeaderRevIter::new(self, Ok(tip.last_block_h)).take(MEDIAN_TIME_WINDOW) {
+			stamps.push(try!(bh).timestamp.to_timespec().sec);
+		}
+		let last = stamps[0];
+		stamps.sort();
+		let median = stamps[stamps.len() / 2];
+		Ok(cmp::max(median, last) as u64 + 1)
 	}
 
 	fn get_block_header(&self, h: &Hash) -> Result<BlockHeader, Error> {
-		option_to_not_found(self.db.get_ser(&to_key(BLOCK_HEADER_PREFIX, &mut h.to_vec())))
+		option_to_not_found(self.get_ser_buffered(&to_key(BLOCK_HEADER_PREFIX, &mut h.to_vec())))
+	}
+
+	fn get_block_header_buf(&self, h: &Hash, buf: &mut Vec<u8>) -> Result<BlockHeader, Error> {
+		let mut key = h.to_vec();
+		option_to_not_found(self.get_ser_buffered_buf(to_key(BLOCK_HEADER_PREFIX, &mut key), buf))
+	}
+
+	fn get_header_by_height(&self, height: u64) -> Result<BlockHeader, Error> {
+		self.get_main_header(height).map(|(_, header)| header)
+	}
+
+	fn find_height_by_timestamp(&self, ts: u64) -> Result<u64, Error> {
+		let header_ts = |height| {
+			self.get_header_by_height(height)
+				.map(|bh| cmp::max(bh.timestamp.to_timespec().sec, 0) as u64)
+		};
+		if try!(header_ts(0)) > ts {
+			return Err(Error::NotFoundErr);
+		}
+		// invariant: block at low is at or before ts, blocks above high are after
+		let (mut low, mut high) = (0, try!(self.head_height()));
+		while low < high {
+			let mid = low + (high - low + 1) / 2;
+			if try!(header_ts(mid)) <= ts {
+				low = mid;
+			} else {
+				high = mid - 1;
+			}
+		}
+		Ok(low)
+	}
+
+	fn iter_main_chain_rev<'a>(&'a self) -> Box<Iterator<Item = Result<BlockHeader, Error>> + 'a> {
+		let start = self.head().map(|head| head.last_block_h);
+		Box::new(HeaderRevIter::new(self, start))
+	}
+
+	fn get_block(&self, h: &Hash) -> Result<Block, Error> {
+		match option_to_not_found(self.get_ser_buffered(&to_key(BLOCK_PREFIX, &mut h.to_vec()))) {
+			Err(Error::NotFoundErr) => {
+				// tell a body we pruned apart from one we never had
+				let horizon = try!(self.body_horizon());
+				match self.get_block_header(h) {
+					Ok(ref bh) if bh.height < horizon => Err(Error::BlockPruned(bh.height)),
+					_ => Err(Error::NotFoundErr),
+				}
+			}
+			res => res,
+		}
+	}
+
+	fn contains_block(&self, h: &Hash) -> Result<bool, Error> {
+		if try!(self.has_block(h)) {
+			return Ok(true);
+		}
+		let horizon = try!(self.body_horizon());
+		match self.get_block_header(h) {
+			Ok(bh) => Ok(bh.height < horizon),
+			Err(Error::NotFoundErr) => Ok(false),
+			Err(e) => Err(e),
+		}
+	}
+
+	fn probably_have(&self, h: &Hash) -> bool {
+		match self.seen {
+			Some(ref seen) => seen.lock().unwrap().probably_contains(h),
+			None => true,
+		}
+	}
+
+	fn body_horizon(&self) -> Result<u64, Error> {
+		let horizon: Option<StoredU64> = try!(self.db
+			.get_ser(&vec![BODY_HORIZON_PREFIX])
+			.map_err(&to_store_err));
+		Ok(horizon.map(|h| h.0).unwrap_or(0))
+	}
+
+	fn prune_bodies(&self, height: u64) -> Result<(), Error> {
+		let horizon = try!(self.body_horizon());
+		if height <= horizon {
+			return Ok(());
+		}
+		if height > try!(self.head_height()) {
+			return Err(Error::InconsistentChain(height, "can't prune above the head".to_string()));
+		}
+		for pheight in horizon..height {
+			let h = try!(self.get_hash_by_height(pheight));
+			try!(self.delete(&to_key(BLOCK_PREFIX, &mut h.to_vec())[..]));
+		}
+		self.put_ser(&vec![BODY_HORIZON_PREFIX], &StoredU64(height))
+	}
+
+	fn delete_block(&self, h: &Hash) -> Result<(), Error> {
+		try!(self.delete(&to_key(BLOCK_PREFIX, &mut h.to_vec())[..]));
+		self.delete(&to_key(BLOCK_HEADER_PREFIX, &mut h.to_vec())[..])
 	}
 
 	fn save_head(&self, t: &Tip) -> Result<(), Error> {
+		try!(self.check_frozen(t));
+		try!(self.check_tip_height(t));
 		try!(self.save_tip(t));
-		self.db.put_ser(&vec![HEAD_PREFIX], t).map_err(&to_store_err)
+		try!(self.setup_height(t));
+		try!(self.put_ser(&vec![HEAD_PREFIX], t));
+		self.notify_head(t);
+		Ok(())
+	}
+
+	fn state_snapshot(&self) -> Result<StateSnapshot, Error> {
+		try!(self.flush());
+		let mut entries = vec![];
+		for key in self.state_keys() {
+			if let Some(value) = try!(self.get_raw(&key)) {
+				entries.push((key, value));
+			}
+		}
+		Ok(StateSnapshot { entries: entries })
+	}
+
+	fn restore_state(&self, s: &StateSnapshot) -> Result<(), Error> {
+		try!(self.flush());
+		for key in self.state_keys() {
+			try!(self.delete(&key));
+		}
+		for &(ref key, ref value) in &s.entries {
+			try!(self.put(key, value.clone()));
+		}
+		self.heights.clear();
+		Ok(())
+	}
+
+	fn events_since(&self, seq: u64) -> Result<Vec<ChainEvent>, Error> {
+		self.db
+			.get_ser_from(&vec![EVENT_PREFIX, SEP], &event_key(seq + 1))
+			.map_err(&to_store_err)
+	}
+
+	fn truncate_events(&self, height: u64) -> Result<(), Error> {
+		let events: Vec<ChainEvent> = try!(self.db
+			.get_ser_prefix(&vec![EVENT_PREFIX, SEP])
+			.map_err(&to_store_err));
+		for e in events.iter().filter(|e| e.height < height) {
+			try!(self.delete(&event_key(e.seq)));
+		}
+		Ok(())
+	}
+
+	fn get_stale_blocks(&self, from_height: u64) -> Result<Vec<Hash>, Error> {
+		let mut from = vec![STALE_PREFIX, SEP];
+		from.write_u64::<BigEndian>(from_height).unwrap();
+		self.db
+			.get_ser_from(&vec![STALE_PREFIX, SEP], &from)
+			.map_err(&to_store_err)
+	}
+
+	fn prune_finalized(&self) -> Result<u64, Error> {
+		let finalized = try!(self.finalized_height());
+		try!(self.truncate_events(finalized));
+		for h in try!(self.get_stale_blocks(0)) {
+			let height = try!(self.get_block_header(&h)).height;
+			if height >= finalized {
+				break;
+			}
+			try!(self.delete(&stale_key(height, &h)));
+		}
+		Ok(finalized)
+	}
+
+	fn subscribe_head(&self) -> Receiver<Tip> {
+		let (tx, rx) = mpsc::sync_channel(SUBSCRIBER_BUFFER);
+		self.subscribers.lock().unwrap().push(tx);
+		rx
 	}
 
 	fn save_tip(&self, t: &Tip) -> Result<(), Error> {
-		let last_branch = t.lineage.last_branch();
-		let mut k = vec![TIP_PREFIX, SEP];
-		k.write_u32::<BigEndian>(last_branch);
-		self.db.put_ser(&mut k, t).map_err(&to_store_err)
+		try!(self.flush());
+		try!(self.put_ser(&tip_key(t), t));
+		self.drop_extra_forks(t)
+	}
+
+	fn delete_tip(&self, t: &Tip) -> Result<(), Error> {
+		try!(self.delete(&tip_key(t)));
+		self.ancestors.invalidate(&t.lineage_hash());
+		Ok(())
+	}
+
+	fn save_orphan(&self, o: &Orphan) -> Result<(), Error> {
+		self.put_ser(&to_key(ORPHAN_PREFIX, &mut o.block.hash().to_vec())[..], o)
+	}
+
+	fn delete_orphan(&self, h: &Hash) -> Result<(), Error> {
+		self.delete(&to_key(ORPHAN_PREFIX, &mut h.to_vec())[..])
+	}
+
+	fn load_orphans(&self, max: usize, ttl_secs: i64) -> Result<Vec<Orphan>, Error> {
+		let mut orphans: Vec<Orphan> = try!(self.db
+			.get_ser_prefix(&vec![ORPHAN_PREFIX, SEP])
+			.map_err(&to_store_err));
+		orphans.sort_by(|a, b| b.received.cmp(&a.received));
+
+		let cutoff = time::get_time().sec - ttl_secs;
+		let mut kept = vec![];
+		for o in orphans {
+			if kept.len() < max && o.received > cutoff {
+				kept.push(o);
+			} else {
+				try!(self.delete_orphan(&o.block.hash()));
+			}
+		}
+		Ok(kept)
+	}
+
+	fn best_header_tip(&self) -> Result<HeaderTip, Error> {
+		option_to_not_found(self.db.get_ser(&vec![HEADER_HEAD_PREFIX]))
+	}
+
+	fn save_header_tip(&self, t: &HeaderTip) -> Result<(), Error> {
+		try!(self.flush());
+		self.put_ser(&vec![HEADER_HEAD_PREFIX], t)
+	}
+
+	fn get_tips(&self) -> Result<Vec<Tip>, Error> {
+		self.db.get_ser_prefix(&vec![TIP_PREFIX, SEP]).map_err(&to_tip_err)
+	}
+
+	fn main_chain_height(&self, h: &Hash) -> Result<Option<u64>, Error> {
+		if let Some(height) = self.heights.get(h) {
+			return Ok(height);
+		}
+		let height = match self.get_block_header(h) {
+			Ok(bh) => {
+				if bh.height <= try!(self.head_height()) &&
+				   try!(self.get_hash_by_height(bh.height)) == *h {
+					Some(bh.height)
+				} else {
+					None
+				}
+			}
+			Err(Error::NotFoundErr) => None,
+			Err(e) => return Err(e),
+		};
+		self.heights.insert(*h, height);
+		Ok(height)
+	}
+
+	fn height_cache_stats(&self) -> CacheStats {
+		self.heights.stats()
+	}
+
+	fn common_ancestor(&self, a: &Tip, b: &Tip) -> u32 {
+		self.ancestors.common_ancestor(&a.lineage, &b.lineage)
+	}
+
+	fn ancestor_cache_stats(&self) -> CacheStats {
+		self.ancestors.stats()
+	}
+
+	fn tracked_fork_count(&self) -> Result<usize, Error> {
+		Ok(try!(self.get_tips()).len())
+	}
+
+	fn tip_for_block(&self, h: &Hash) -> Result<Tip, Error> {
+		let tips = try!(self.get_tips());
+		tips.into_iter().find(|t| t.last_block_h == *h).ok_or(Error::NotFoundErr)
+	}
+
+	fn fork_tip(&self, h: &Hash) -> Result<Tip, Error> {
+		let header = try!(self.get_block_header(h));
+		let tips = try!(self.get_tips());
+		let mut on: Option<Tip> = None;
+		for t in &tips {
+			if t.height < header.height || t.height - header.height > MAX_CHAIN_DIFF as u64 {
+				continue;
+			}
+			if on.as_ref().map_or(false, |o| o.lineage.depth() <= t.lineage.depth()) {
+				continue;
+			}
+			if let Some(tip) = try!(self.tip_down_to(t, &header)) {
+				on = Some(tip);
+			}
+		}
+		let on = try!(on.ok_or(Error::NotFoundErr));
+		let branch = tips.iter().map(|t| t.lineage.last_branch()).max().unwrap_or(0) + 1;
+		Ok(Tip { lineage: on.lineage.fork(branch), ..on })
+	}
+
+	fn tip_as_of(&self, height: u64) -> Result<Tip, Error> {
+		let head = try!(self.head());
+		if height > head.height {
+			return Err(Error::NotFoundErr);
+		}
+		// take the difficulty of the blocks above back out of the total
+		let mut total_difficulty = head.total_difficulty;
+		for above in (height + 1)..(head.height + 1) {
+			let bh = try!(self.get_header_by_height(above));
+			total_difficulty = try!(sub_difficulty(total_difficulty, &bh));
+		}
+		let bh = try!(self.get_header_by_height(height));
+		Ok(Tip {
+			height: height,
+			last_block_h: bh.hash(),
+			prev_block_h: if height == 0 { bh.hash() } else { bh.previous },
+			total_difficulty: total_difficulty,
+			lineage: head.lineage,
+		})
+	}
+
+	fn confirmed_tip(&self, depth: u64) -> Result<Tip, Error> {
+		let height = try!(self.head_height());
+		if depth > height {
+			return Err(Error::NotFoundErr);
+		}
+		self.tip_as_of(height - depth)
+	}
+
+	fn is_at_best_known_tip(&self, peer_tips: &[Tip]) -> Result<bool, Error> {
+		let head = try!(self.head());
+		Ok(peer_tips.iter().all(|t| head.total_difficulty >= t.total_difficulty))
+	}
+
+	fn add_difficulty_checkpoint(&self, height: u64, total: Difficulty) -> Result<(), Error> {
+		let checkpoint = DifficultyCheckpoint {
+			height: height,
+			total_difficulty: total,
+		};
+		self.put_ser(&diff_checkpoint_key(height), &checkpoint)
+	}
+
+	fn get_difficulty_checkpoint(&self, height: u64) -> Result<Difficulty, Error> {
+		let checkpoint: DifficultyCheckpoint =
+			try!(option_to_not_found(self.db.get_ser(&diff_checkpoint_key(height))));
+		Ok(checkpoint.total_difficulty)
+	}
+
+	fn difficulty_checkpoints(&self) -> Result<Vec<DifficultyCheckpoint>, Error> {
+		self.db
+			.get_ser_prefix(&vec![DIFF_CHECKPOINT_PREFIX, SEP])
+			.map_err(&to_store_err)
 	}
+
+	fn finalized_height(&self) -> Result<u64, Error> {
+		let head = try!(self.head());
+		let mut finalized = head.height.saturating_sub(FINALITY_DEPTH);
+		// a block we have checkpointed is final as well, with all below it
+		for c in try!(self.difficulty_checkpoints()) {
+			if c.height <= head.height && c.height + 1 > finalized {
+				finalized = c.height + 1;
+			}
+		}
+		Ok(finalized)
+	}
+
+	fn finalized_notified_height(&self) -> Result<u64, Error> {
+		let notified: Option<StoredU64> = try!(self.db
+			.get_ser(&vec![FINALIZED_NOTIFIED_PREFIX])
+			.map_err(&to_store_err));
+		Ok(notified.map(|n| n.0).unwrap_or(0))
+	}
+
+	fn save_finalized_notified_height(&self, height: u64) -> Result<(), Error> {
+		self.put_ser(&vec![FINALIZED_NOTIFIED_PREFIX], &StoredU64(height))
+	}
+
+	fn force_head(&self, h: &Hash) -> Result<Tip, Error> {
+		let head = try!(self.head());
+		if *h == head.last_block_h {
+			return Ok(head);
+		}
+		// same as the pipeline, we can't fork from within a fork
+		let tip = try!(self.tip_for_block(h));
+
+		// walk down the fork to the main chain, checking we have all bodies
+		let mut header = try!(self.get_block_header(h));
+		loop {
+			match self.get_hash_by_height(header.height) {
+				Ok(main) if main == header.hash() => break,
+				Ok(_) | Err(Error::NotFoundErr) => {}
+				Err(e) => return Err(e),
+			}
+			if !try!(self.has_block(&header.hash())) {
+				return Err(Error::InconsistentChain(header.height,
+				                                    format!("missing body for {}", header.hash())));
+			}
+			header = try!(self.get_block_header(&header.previous));
+		}
+		// the blocks above the fork get disconnected, none can be final
+		if header.height + 1 < try!(self.finalized_height()) {
+			return Err(Error::ForkBelowFinalized(header.height));
+		}
+
+		warn!("Forcing head from {} at {} to {} at {}.",
+		      head.last_block_h,
+		      head.height,
+		      tip.last_block_h,
+		      tip.height);
+		try!(self.save_head(&tip));
+		try!(self.log_events(vec![(ChainEventKind::Forced, tip.last_block_h, tip.height)]
+			.into_iter()));
+		Ok(tip)
+	}
+
+	fn main_chain_proof(&self, h: &Hash) -> Result<MainChainProof, Error> {
+		let head = try!(self.head());
+		let header = try!(self.get_block_header(h));
+		if header.height > head.height || try!(self.get_hash_by_height(header.height)) != *h {
+			return Err(Error::NotFoundErr);
+		}
+		let depth = head.height - header.height;
+		if depth > MAX_PROOF_DEPTH {
+			return Err(Error::ProofTooDeep(depth));
+		}
+		let mut headers = Vec::with_capacity(depth as usize + 1);
+		for height in header.height..(head.height + 1) {
+			headers.push(try!(self.get_header_by_height(height)));
+		}
+		Ok(MainChainProof { headers: headers })
+	}
+
+	fn block_locator(&self) -> Result<BlockLocator, Error> {
+		let head = try!(self.head());
+		let mut locator = BlockLocator {
+			hashes: vec![],
+			heights: vec![],
+		};
+		let mut height = head.height;
+		let mut step = 1;
+		// keeping the last spot for genesis
+		while height > 0 && locator.hashes.len() < MAX_LOCATOR_LEN - 1 {
+			locator.hashes.push(try!(self.get_hash_by_height(height)));
+			locator.heights.push(height);
+			height = height.saturating_sub(step);
+			if locator.hashes.len() > 1 {
+				step *= 2;
+			}
+		}
+		locator.hashes.push(try!(self.get_hash_by_height(0)));
+		locator.heights.push(0);
+		Ok(locator)
+	}
+
+	fn first_divergence(&self,
+	                    other_locator: &BlockLocator)
+	                    -> Result<Option<(u64, Hash, Hash)>, Error> {
+		let head_height = try!(self.head_height());
+		// the first block of the locator that's on our main chain is the last
+		// one in common
+		let mut common = None;
+		let locator = other_locator.hashes.iter().zip(other_locator.heights.iter());
+		for (n, (h, height)) in locator.enumerate() {
+			match self.get_hash_by_height(*height) {
+				Ok(ours) if ours == *h => {
+					common = Some((n, *height));
+					break;
+				}
+				Ok(_) | Err(Error::NotFoundErr) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		match common {
+			// their head is on our main chain
+			Some((0, _)) => Ok(None),
+			// our head is on theirs
+			Some((_, height)) if height == head_height => Ok(None),
+			Some((n, height)) => {
+				let ours = try!(self.get_hash_by_height(height + 1));
+				Ok(Some((height + 1, ours, other_locator.hashes[n - 1])))
+			}
+			// not even the same genesis
+			None => {
+				match other_locator.hashes.last() {
+					Some(theirs) => Ok(Some((0, try!(self.get_hash_by_height(0)), *theirs))),
+					None => Ok(None),
+				}
+			}
+		}
+	}
+
+	fn chain_diff(&self, peer_tip: &Tip) -> Result<ChainDiff, Error> {
+		let mut ours = try!(self.head_header());
+		let mut theirs = try!(self.get_block_header(&peer_tip.last_block_h));
+		let mut missing = vec![];
+		let mut disconnect = vec![];
+
+		// walk both chains back, tallest first, until they meet
+		while ours.hash() != theirs.hash() {
+			if ours.height >= theirs.height {
+				missing.push(ours.hash());
+				ours = try!(self.get_block_header(&ours.previous));
+			} else {
+				disconnect.push(theirs.hash());
+				theirs = try!(self.get_block_header(&theirs.previous));
+			}
+			if missing.len() > MAX_CHAIN_DIFF || disconnect.len() > MAX_CHAIN_DIFF {
+				return Err(Error::DiffTooLarge);
+			}
+		}
+		missing.reverse();
+		Ok(ChainDiff {
+			missing: missing,
+			disconnect: disconnect,
+		})
+	}
+
+	fn verify_chain(&self, from_height: u64) -> Result<(), Error> {
+		let head = try!(self.head());
+		try!(self.check_tip_height(&head));
+		try!(head.verify_total_difficulty(self));
+		for t in try!(self.get_tips()) {
+			try!(self.check_tip_height(&t));
+		}
+		let horizon = try!(self.body_horizon());
+		let mut prev = if from_height > 0 && from_height <= head.height {
+			Some(try!(self.get_header_by_height(from_height - 1)))
+		} else {
+			None
+		};
+		let mut last = None;
+		for height in from_height..(head.height + 1) {
+			let inconsistent = |reason: &str| Error::InconsistentChain(height, reason.to_string());
+			let h = match self.get_indexed_hash(height) {
+				Ok(h) => h,
+				Err(Error::NotFoundErr) => return Err(inconsistent("missing height index entry")),
+				Err(e) => return Err(e),
+			};
+			let header = match self.get_block_header(&h) {
+				Ok(header) => header,
+				Err(Error::NotFoundErr) => return Err(inconsistent("missing header")),
+				Err(e) => return Err(e),
+			};
+			if header.height != height {
+				return Err(inconsistent("header height doesn't match the height index"));
+			}
+			if let Some(p) = prev {
+				if header.previous != p.hash() {
+					return Err(inconsistent("header doesn't link to the previous one"));
+				}
+				if header.height != p.height + 1 {
+					return Err(inconsistent("height doesn't follow the previous one"));
+				}
+			}
+			if height >= horizon && !try!(self.has_block(&h)) {
+				return Err(inconsistent("missing block body"));
+			}
+			last = Some(h);
+			prev = Some(header);
+		}
+		if let Some(h) = last {
+			if h != head.last_block_h {
+				return Err(Error::InconsistentChain(head.height,
+				                                    "height index doesn't end at the head".to_string()));
+			}
+		}
+		Ok(())
+	}
+
+	fn replay(&self, from_height: u64, adapter: &ChainAdapter) -> Result<u64, Error> {
+		let head = try!(self.head());
+		// refuse upfront rather than stopping halfway through
+		let horizon = try!(self.body_horizon());
+		if from_height < horizon && from_height <= head.height {
+			return Err(Error::BlockPruned(from_height));
+		}
+		let mut prev: Option<Hash> = None;
+		let mut replayed = 0;
+		for height in from_height..(head.height + 1) {
+			let b = try!(self.get_block(&try!(self.get_hash_by_height(height))));
+			// the main chain changing under us would break the order
+			if b.header.height != height || prev.map_or(false, |p| b.header.previous != p) {
+				return Err(Error::InconsistentChain(height, "main chain changed during replay".to_string()));
+			}
+			adapter.block_replayed(&b);
+			prev = Some(b.hash());
+			replayed += 1;
+		}
+		Ok(replayed)
+	}
+
+	fn rebuild_height_index(&self) -> Result<u64, Error> {
+		let head = try!(self.head());
+		let mut header = try!(self.get_block_header(&head.last_block_h));
+		if header.height != head.height {
+			return Err(Error::TipHeightMismatch {
+				tip_height: head.height,
+				header_height: header.height,
+			});
+		}
+		let mut written = 0;
+		loop {
+			try!(self.put(&height_key(header.height), header.hash().to_vec()));
+			written += 1;
+			if header.height == 0 {
+				break;
+			}
+			header = try!(self.get_block_header(&header.previous));
+		}
+
+		self.heights.clear();
+		self.index_fallback_warned.store(false, Ordering::Relaxed);
+
+		// leftovers above the head
+		let mut height = head.height + 1;
+		while try!(self.get_raw(&height_key(height))).is_some() {
+			try!(self.delete(&height_key(height)));
+			height += 1;
+		}
+		Ok(written)
+	}
+
+	fn progress_since(&self,
+	                  baseline_height: u64,
+	                  baseline_time: u64,
+	                  now: u64)
+	                  -> Result<SyncProgress, Error> {
+		let head = try!(self.head());
+		Ok(SyncProgress::new(baseline_height, baseline_time, head.height, now))
+	}
+}
+
+impl ChainKVStore {
+	// Main chain block at the provided height.
+	fn get_hash_by_height(&self, height: u64) -> Result<Hash, Error> {
+		self.get_main_header(height).map(|(h, _)| h)
+	}
+
+	// Main chain block hash and header at the provided height, the header
+	// being the one read to check the height index entry. Falls back to
+	// walking the chain down from the head when the entry is missing or
+	// points to a block at another height, warning only the first time until
+	// the index gets rebuilt.
+	fn get_main_header(&self, height: u64) -> Result<(Hash, BlockHeader), Error> {
+		match self.get_indexed_hash(height) {
+			Ok(h) => {
+				match self.get_block_header(&h) {
+					Ok(header) => {
+						if header.height == height {
+							return Ok((h, header));
+						}
+					}
+					Err(Error::NotFoundErr) => {}
+					Err(e) => return Err(e),
+				}
+			}
+			Err(Error::NotFoundErr) => {}
+			Err(e) => return Err(e),
+		}
+		let head = try!(self.head());
+		if height > head.height {
+			return Err(Error::NotFoundErr);
+		}
+		if !self.index_fallback_warned.swap(true, Ordering::Relaxed) {
+			warn!("Height index entry at {} missing or inconsistent, walking the chain from the \
+			       head. Further fallbacks won't be logged until the index is rebuilt.",
+			      height);
+		}
+		let mut header = try!(self.get_block_header(&head.last_block_h));
+		while header.height > height {
+			header = try!(self.get_block_header(&header.previous));
+		}
+		Ok((header.hash(), header))
+	}
+
+	// Height index entry, as is
+	fn get_indexed_hash(&self, height: u64) -> Result<Hash, Error> {
+		option_to_not_found(self.db.get_ser(&height_key(height)))
+	}
+
+	// The tip taken down to the provided block, if the block is on its fork,
+	// taking the difficulty of the blocks above back out of the total
+	fn tip_down_to(&self, t: &Tip, target: &BlockHeader) -> Result<Option<Tip>, Error> {
+		let mut total_difficulty = t.total_difficulty;
+		let mut header = try!(self.get_block_header(&t.last_block_h));
+		while header.height > target.height {
+			total_difficulty = try!(sub_difficulty(total_difficulty, &header));
+			header = try!(self.get_block_header(&header.previous));
+		}
+		let h = header.hash();
+		if h != target.hash() {
+			return Ok(None);
+		}
+		Ok(Some(Tip {
+			height: header.height,
+			last_block_h: h,
+			prev_block_h: if header.height == 0 { h } else { header.previous },
+			total_difficulty: total_difficulty,
+			lineage: t.lineage.clone(),
+		}))
+	}
+
+	// Keys of all the chain state entries currently in the db
+	fn state_keys(&self) -> Vec<Vec<u8>> {
+		let mut keys = vec![];
+		for prefix in STATE_PREFIXES.iter() {
+			self.db.for_each_key(&[*prefix], |k| keys.push(k.to_vec()));
+		}
+		keys
+	}
+
+	// Makes sure the tip height is the one of its last block
+	fn check_tip_height(&self, t: &Tip) -> Result<(), Error> {
+		let header = try!(self.get_block_header(&t.last_block_h));
+		if header.height != t.height {
+			return Err(Error::TipHeightMismatch {
+				tip_height: t.height,
+				header_height: header.height,
+			});
+		}
+		Ok(())
+	}
+
+	// Refuses to move the head above the height the chain is frozen at
+	#[cfg(feature = "testing")]
+	fn check_frozen(&self, t: &Tip) -> Result<(), Error> {
+		match self.frozen_at() {
+			Some(height) if t.height > height => Err(Error::ChainFrozen(height)),
+			_ => Ok(()),
+		}
+	}
+
+	#[cfg(not(feature = "testing"))]
+	fn check_frozen(&self, _: &Tip) -> Result<(), Error> {
+		Ok(())
+	}
+
+	// Drops the lightest tips below the finalized height while over the
+	// tracked forks limit, never the head nor the provided tip just saved.
+	// Blocks still leading to a kept tip stay.
+	fn drop_extra_forks(&self, saved: &Tip) -> Result<(), Error> {
+		let max = match self.max_tracked_forks {
+			Some(max) => max,
+			None => return Ok(()),
+		};
+		let mut tips = try!(self.get_tips());
+		if tips.len() <= max {
+			return Ok(());
+		}
+		let head = match self.head() {
+			Ok(head) => head,
+			Err(Error::NotFoundErr) => return Ok(()),
+			Err(e) => return Err(e),
+		};
+		let finalized = try!(self.finalized_height());
+		let protected = vec![tip_key(&head), tip_key(saved)];
+
+		// lightest first
+		tips.sort_by(|a, b| a.total_difficulty.cmp(&b.total_difficulty));
+		let mut extra = tips.len() - max;
+		let mut dropped = vec![];
+		let mut kept = vec![];
+		for t in tips {
+			if extra > 0 && t.height < finalized && !protected.contains(&tip_key(&t)) {
+				extra -= 1;
+				dropped.push(t);
+			} else {
+				kept.push(t);
+			}
+		}
+		if dropped.is_empty() {
+			return Ok(());
+		}
+
+		let mut shared = HashSet::new();
+		for t in &kept {
+			for (h, _) in try!(self.side_blocks(t, &head)) {
+				shared.insert(h);
+			}
+		}
+		for t in dropped {
+			info!("Dropping fork at {} with total difficulty {}, over the tracked forks limit.",
+			      t.height,
+			      t.total_difficulty.0);
+			try!(self.delete_tip(&t));
+			for (h, height) in try!(self.side_blocks(&t, &head)) {
+				if shared.contains(&h) {
+					break;
+				}
+				try!(self.delete_block(&h));
+				try!(self.delete(&stale_key(height, &h)));
+			}
+		}
+		Ok(())
+	}
+
+	// Blocks of the provided tip that aren't on the main chain of the
+	// provided head, from the top
+	fn side_blocks(&self, t: &Tip, head: &Tip) -> Result<Vec<(Hash, u64)>, Error> {
+		let mut blocks = vec![];
+		let mut h = t.last_block_h;
+		loop {
+			let header = match self.get_block_header(&h) {
+				Ok(header) => header,
+				Err(Error::NotFoundErr) => break,
+				Err(e) => return Err(e),
+			};
+			if header.height <= head.height && try!(self.get_hash_by_height(header.height)) == h {
+				break;
+			}
+			blocks.push((h, header.height));
+			if header.height == 0 {
+				break;
+			}
+			h = header.previous;
+		}
+		Ok(blocks)
+	}
+
+	// Whether we have the body of the block, without deserializing it
+	fn has_block(&self, h: &Hash) -> Result<bool, Error> {
+		let body = try!(self.get_raw(&to_key(BLOCK_PREFIX, &mut h.to_vec())[..]));
+		Ok(body.is_some())
+	}
+
+	// Raw value for the key, looking in the write buffer first
+	fn get_raw(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+		if let Some(data) = self.pending.lock().unwrap().entries.get(key) {
+			return Ok(Some(data.clone()));
+		}
+		self.db.get(key).map_err(&to_store_err)
+	}
+
+	// Points the height index to the main chain ending at the provided new
+	// head. Rewrites entries until the fork point with the previous main chain
+	// and removes the ones above the new head. Logs the blocks leaving the main
+	// chain, from the top, then the ones joining it, from the bottom.
+	fn setup_height(&self, t: &Tip) -> Result<(), Error> {
+		let mut disconnected = vec![];
+		let mut connected = vec![];
+		if let Ok(old_height) = self.head_height() {
+			for height in ((t.height + 1)..(old_height + 1)).rev() {
+				if let Ok(old) = self.get_indexed_hash(height) {
+					disconnected.push((old, height));
+				}
+				try!(self.delete(&height_key(height)));
+			}
+		}
+		let mut header = try!(self.get_block_header(&t.last_block_h));
+		loop {
+			let h = header.hash();
+			match self.get_indexed_hash(header.height) {
+				Ok(indexed) if indexed == h => break,
+				Ok(indexed) => disconnected.push((indexed, header.height)),
+				_ => {}
+			}
+			try!(self.put(&height_key(header.height), h.to_vec()));
+			connected.push((h, header.height));
+			if header.height == 0 {
+				break;
+			}
+			header = try!(self.get_block_header(&header.previous));
+		}
+
+		// blocks leaving the main chain are stale until they come back
+		for &(h, height) in &disconnected {
+			try!(self.put(&stale_key(height, &h), h.to_vec()));
+			self.heights.insert(h, None);
+		}
+		for &(h, height) in &connected {
+			try!(self.delete(&stale_key(height, &h)));
+			self.heights.insert(h, Some(height));
+		}
+
+		let events = disconnected.into_iter()
+			.map(|(h, height)| (ChainEventKind::Disconnected, h, height))
+			.chain(connected.into_iter().rev().map(|(h, height)| (ChainEventKind::Connected, h, height)));
+		self.log_events(events)
+	}
+
+	// Appends the provided events to the log, numbering them after the last
+	// sequence number used.
+	fn log_events<I>(&self, events: I) -> Result<(), Error>
+		where I: Iterator<Item = (ChainEventKind, Hash, u64)>
+	{
+		let seq_key = vec![EVENT_SEQ_PREFIX];
+		let last: Option<StoredU64> = try!(self.db.get_ser(&seq_key).map_err(&to_store_err));
+		let mut seq = last.map(|s| s.0).unwrap_or(0);
+		let start = seq;
+		for (kind, h, height) in events {
+			seq += 1;
+			let e = ChainEvent {
+				seq: seq,
+				kind: kind,
+				hash: h,
+				height: height,
+			};
+			try!(self.put_ser(&event_key(seq), &e));
+		}
+		if seq > start {
+			try!(self.put_ser(&seq_key, &StoredU64(seq)));
+		}
+		Ok(())
+	}
+}
+
+/// Copies the content of a chain store into another freshly initialized one:
+/// all main chain blocks in height order, the blocks of the other forks we
+/// track, all tips and finally the head. Returns the number of blocks copied
+/// the destination didn't have. On error, the destination gets its chain
+/// state back as it was before and the blocks copied are deleted, the ones it
+/// already had kept.
+pub fn migrate(src: &ChainStore, dst: &ChainStore) -> Result<u64, Error> {
+	let state = try!(dst.state_snapshot());
+	let mut blocks = vec![];
+	let res = copy_store(src, dst, &mut blocks);
+	if res.is_err() {
+		// the state first, so the height index stops pointing to the blocks
+		if let Err(e) = dst.restore_state(&state) {
+			warn!("Could not restore the chain state after a failed migration: {:?}", e);
+		}
+		for h in &blocks {
+			let _ = dst.delete_block(h);
+		}
+	}
+	res
+}
+
+// Does the actual copy for migrate, keeping track of the blocks copied that
+// the destination didn't have so they can be rolled back.
+fn copy_store(src: &ChainStore, dst: &ChainStore, blocks: &mut Vec<Hash>) -> Result<u64, Error> {
+	let head = try!(src.head());
+
+	// main chain from genesis to head
+	let mut main_chain = vec![head.last_block_h];
+	let mut header = try!(src.get_block_header(&head.last_block_h));
+	while header.height > 0 {
+		main_chain.push(header.previous);
+		header = try!(src.get_block_header(&header.previous));
+	}
+	main_chain.reverse();
+	for h in &main_chain {
+		let known = try!(has_header(dst, h));
+		match src.get_block(h) {
+			Ok(b) => try!(dst.save_block(&b)),
+			Err(Error::BlockPruned(_)) => try!(dst.save_block_header(&try!(src.get_block_header(h)))),
+			Err(e) => return Err(e),
+		}
+		if !known {
+			blocks.push(*h);
+		}
+	}
+
+	// side forks, down to where they meet the main chain
+	let on_main: HashSet<Hash> = main_chain.iter().cloned().collect();
+	for tip in try!(src.get_tips()) {
+		let mut fork = vec![];
+		let mut h = tip.last_block_h;
+		while !on_main.contains(&h) {
+			let b = try!(src.get_block(&h));
+			h = b.header.previous;
+			fork.push(b);
+		}
+		for b in fork.iter().rev() {
+			let h = b.hash();
+			let known = try!(has_header(dst, &h));
+			try!(dst.save_block(b));
+			if !known {
+				blocks.push(h);
+			}
+		}
+		try!(dst.save_tip(&tip));
+	}
+
+	try!(dst.save_head(&head));
+	let dst_head = try!(dst.head());
+	if dst_head.last_block_h != head.last_block_h || dst_head.height != head.height {
+		let reason = "head mismatch after migration".to_string();
+		return Err(Error::StorageErr(StorageError::Corruption(reason)));
+	}
+	try!(dst.prune_bodies(try!(src.body_horizon())));
+	Ok(blocks.len() as u64)
+}
+
+fn has_header(store: &ChainStore, h: &Hash) -> Result<bool, Error> {
+	match store.get_block_header(h) {
+		Ok(_) => Ok(true),
+		Err(Error::NotFoundErr) => Ok(false),
+		Err(e) => Err(e),
+	}
+}
+
+/// Partial deserialization of a tip, only reading its height.
+struct TipHeight(u64);
+
+impl ser::Readable<TipHeight> for TipHeight {
+	fn read(reader: &mut ser::Reader) -> Result<TipHeight, ser::Error> {
+		Ok(TipHeight(try!(reader.read_u64())))
+	}
+}
+
+/// A single number kept in store, like the last sequence number used in the
+/// event log or the body horizon.
+struct StoredU64(u64);
+
+impl ser::Writeable for StoredU64 {
+	fn write(&self, writer: &mut ser::Writer) -> Result<(), ser::Error> {
+		writer.write_u64(self.0)
+	}
+}
+
+impl ser::Readable<StoredU64> for StoredU64 {
+	fn read(reader: &mut ser::Reader) -> Result<StoredU64, ser::Error> {
+		Ok(StoredU64(try!(reader.read_u64())))
+	}
+}
+
+fn height_key(height: u64) -> Vec<u8> {
+	let mut k = vec![HEIGHT_PREFIX, SEP];
+	k.write_u64::<BigEndian>(height).unwrap();
+	k
+}
+
+fn diff_checkpoint_key(height: u64) -> Vec<u8> {
+	let mut k = vec![DIFF_CHECKPOINT_PREFIX, SEP];
+	k.write_u64::<BigEndian>(height).unwrap();
+	k
+}
+
+fn stale_key(height: u64, h: &Hash) -> Vec<u8> {
+	let mut k = vec![STALE_PREFIX, SEP];
+	k.write_u64::<BigEndian>(height).unwrap();
+	k.extend_from_slice(&h.to_vec());
+	k
+}
+
+fn event_key(seq: u64) -> Vec<u8> {
+	let mut k = vec![EVENT_PREFIX, SEP];
+	k.write_u64::<BigEndian>(seq).unwrap();
+	k
+}
+
+fn tip_key(t: &Tip) -> Vec<u8> {
+	let mut k = vec![TIP_PREFIX, SEP];
+	k.write_u32::<BigEndian>(t.lineage.last_branch()).unwrap();
+	k
 }
 
 fn to_key(prefix: u8, val: &mut Vec<u8>) -> &mut Vec<u8> {
@@ -87,8 +1667,50 @@ fn to_key(prefix: u8, val: &mut Vec<u8>) -> &mut Vec<u8> {
 	val
 }
 
+// Prefixes of the RocksDB statuses for failures that may go away when
+// retried, in both their long and short forms
+const TRANSIENT_DB_ERRORS: &'static [&'static str] = &["IO error",
+                                                       "Resource busy",
+                                                       "Busy",
+                                                       "Operation timed out",
+                                                       "TimedOut",
+                                                       "Operation failed. Try again",
+                                                       "Try again"];
+
 fn to_store_err(e: grin_store::Error) -> Error {
-	Error::StorageErr(format!("{:?}", e))
+	match e {
+		// RocksDB reports the kind of failure with its status prefix
+		grin_store::Error::RocksDbErr(s) => {
+			if s.starts_with("Corruption") {
+				Error::StorageErr(StorageError::Corruption(s))
+			} else if TRANSIENT_DB_ERRORS.iter().any(|p| s.starts_with(p)) {
+				Error::StorageErr(StorageError::Io(s))
+			} else {
+				Error::StorageErr(StorageError::Other(s))
+			}
+		}
+		grin_store::Error::SerErr(e) => {
+			Error::StorageErr(StorageError::Serialization(format!("{:?}", e)))
+		}
+	}
+}
+
+/// Same as to_store_err, reporting a tip failing its consistency checks as corrupt
+fn to_tip_err(e: grin_store::Error) -> Error {
+	match e {
+		grin_store::Error::SerErr(ser::Error::CorruptedData) => {
+			Error::StorageErr(StorageError::Corruption("corrupt tip".to_string()))
+		}
+		_ => to_store_err(e),
+	}
+}
+
+fn tip_or_not_found(res: Result<Option<Tip>, grin_store::Error>) -> Result<Tip, Error> {
+	match res {
+		Ok(None) => Err(Error::NotFoundErr),
+		Ok(Some(t)) => Ok(t),
+		Err(e) => Err(to_tip_err(e)),
+	}
 }
 
 /// unwraps the inner option by converting the none case to a not found error
@@ -99,3 +1721,591 @@ fn option_to_not_found<T>(res: Result<Option<T>, grin_store::Error>) -> Result<T
 		Err(e) => Err(to_store_err(e)),
 	}
 }
+
+#[cfg(any(test, feature = "testing"))]
+static NEXT_TEMP_DIR: ::std::sync::atomic::AtomicUsize =
+	::std::sync::atomic::ATOMIC_USIZE_INIT;
+
+/// Directory of a throwaway store under the system temp dir, new for each
+/// instance and removed with everything in it when dropped. For tests, only
+/// available with the testing feature.
+#[cfg(any(test, feature = "testing"))]
+pub struct TempDir(::std::path::PathBuf);
+
+#[cfg(any(test, feature = "testing"))]
+impl TempDir {
+	/// New empty directory, its name starting with the provided prefix.
+	pub fn new(prefix: &str) -> TempDir {
+		let n = NEXT_TEMP_DIR.fetch_add(1, Ordering::SeqCst);
+		let name = format!("{}_{}_{}", prefix, time::precise_time_ns(), n);
+		let path = ::std::env::temp_dir().join(name);
+		let _ = ::std::fs::remove_dir_all(&path);
+		TempDir(path)
+	}
+
+	/// Path of the directory, to open a store in.
+	pub fn path(&self) -> String {
+		self.0.to_string_lossy().into_owned()
+	}
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Drop for TempDir {
+	fn drop(&mut self) {
+		let _ = ::std::fs::remove_dir_all(&self.0);
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::cell::Cell;
+
+	use super::*;
+
+	#[test]
+	fn retry_transient_writes() {
+		let policy = RetryPolicy {
+			max_retries: 2,
+			base_delay_ms: 1,
+		};
+		let dir = TempDir::new("grin_retry");
+		let store = ChainKVStore::new(dir.path()).unwrap().with_retry_policy(policy);
+
+		// fails twice then succeeds
+		let attempts = Cell::new(0);
+		store.with_retry(|| {
+				attempts.set(attempts.get() + 1);
+				if attempts.get() <= 2 {
+					Err(grin_store::Error::RocksDbErr("IO error: busy".to_string()))
+				} else {
+					Ok(())
+				}
+			})
+			.unwrap();
+		assert_eq!(attempts.get(), 3);
+
+		// out of retries
+		attempts.set(0);
+		let res = store.with_retry(|| {
+			attempts.set(attempts.get() + 1);
+			Err(grin_store::Error::RocksDbErr("IO error: busy".to_string()))
+		});
+		match res {
+			Err(Error::StorageErr(StorageError::Io(_))) => {}
+			_ => panic!("should fail with an io error"),
+		}
+		assert_eq!(attempts.get(), 3);
+
+		// corruption is never retried
+		attempts.set(0);
+		let res = store.with_retry(|| {
+			attempts.set(attempts.get() + 1);
+			Err(grin_store::Error::RocksDbErr("Corruption: bad block".to_string()))
+		});
+		match res {
+			Err(Error::StorageErr(StorageError::Corruption(_))) => {}
+			_ => panic!("should fail with a corruption error"),
+		}
+		assert_eq!(attempts.get(), 1);
+
+		// neither are failures retrying can't fix
+		attempts.set(0);
+		let res = store.with_retry(|| {
+			attempts.set(attempts.get() + 1);
+			Err(grin_store::Error::RocksDbErr("Invalid argument: bad option".to_string()))
+		});
+		match res {
+			Err(Error::StorageErr(StorageError::Other(_))) => {}
+			_ => panic!("should fail with another storage error"),
+		}
+		assert_eq!(attempts.get(), 1);
+
+		// while a busy db is
+		attempts.set(0);
+		let _ = store.with_retry(|| {
+			attempts.set(attempts.get() + 1);
+			Err(grin_store::Error::RocksDbErr("Resource busy: ".to_string()))
+		});
+		assert_eq!(attempts.get(), 3);
+
+		// unless retries are disabled
+		let store = store.with_retry_policy(RetryPolicy::none());
+		attempts.set(0);
+		let _ = store.with_retry(|| {
+			attempts.set(attempts.get() + 1);
+			Err(grin_store::Error::RocksDbErr("IO error: busy".to_string()))
+		});
+		assert_eq!(attempts.get(), 1);
+	}
+
+	#[cfg(feature = "testing")]
+	#[test]
+	fn freeze_chain() {
+		let dir = TempDir::new("grin_frozen");
+		let store = ChainKVStore::new(dir.path()).unwrap();
+		let gen = core::genesis::genesis();
+		store.init(&gen).unwrap();
+		let mut b1 = core::genesis::genesis();
+		b1.header.height = 1;
+		b1.header.previous = gen.hash();
+		store.save_block(&b1).unwrap();
+		let tip = store.head().unwrap().append(&b1.header).unwrap();
+
+		store.freeze(0);
+		match store.save_head(&tip) {
+			Err(Error::ChainFrozen(0)) => {}
+			_ => panic!("should refuse to move a frozen chain"),
+		}
+		assert_eq!(store.head().unwrap().height, 0);
+		assert_eq!(store.get_block(&b1.hash()).unwrap().hash(), b1.hash());
+
+		store.unfreeze();
+		store.save_head(&tip).unwrap();
+		assert_eq!(store.head().unwrap().height, 1);
+	}
+
+	#[test]
+	fn check_tip_height() {
+		let dir = TempDir::new("grin_tip_height");
+		let store = ChainKVStore::new(dir.path()).unwrap();
+		let gen = core::genesis::genesis();
+		store.init(&gen).unwrap();
+
+		let off_by_one = Tip { height: 1, ..Tip::genesis(&gen) };
+		match store.save_head(&off_by_one) {
+			Err(Error::TipHeightMismatch { tip_height: 1, header_height: 0 }) => {}
+			_ => panic!("should refuse a head with the wrong height"),
+		}
+		store.verify_chain(0).unwrap();
+
+		let bad_tip = Tip { lineage: off_by_one.lineage.fork(1), ..off_by_one };
+		store.save_tip(&bad_tip).unwrap();
+		match store.verify_chain(0) {
+			Err(Error::TipHeightMismatch { .. }) => {}
+			_ => panic!("should catch a tip with the wrong height"),
+		}
+		store.delete_tip(&bad_tip).unwrap();
+	}
+
+	#[test]
+	fn buffer_block_writes() {
+		let policy = BufferPolicy {
+			max_blocks: 2,
+			max_delay_ms: 60 * 1000,
+		};
+		let dir = TempDir::new("grin_buffer");
+		let store = ChainKVStore::new(dir.path()).unwrap().with_write_buffer(policy);
+		let gen = core::genesis::genesis();
+		let key = to_key(BLOCK_PREFIX, &mut gen.hash().to_vec()).clone();
+
+		// readable right away but not written yet
+		store.save_block(&gen).unwrap();
+		assert_eq!(store.get_block(&gen.hash()).unwrap().hash(), gen.hash());
+		assert!(store.db.get(&key[..]).unwrap().is_none());
+
+		// a full buffer gets flushed
+		let mut b1 = core::genesis::genesis();
+		b1.header.height = 1;
+		b1.header.previous = gen.hash();
+		store.save_block_header(&b1.header).unwrap();
+		assert!(store.db.get(&key[..]).unwrap().is_some());
+		assert_eq!(store.get_block_header(&b1.hash()).unwrap().height, 1);
+
+		// and so does moving a tip
+		store.save_block(&b1).unwrap();
+		store.save_head(&Tip::genesis(&gen).append(&b1.header).unwrap()).unwrap();
+		assert!(store.pending.lock().unwrap().entries.is_empty());
+		assert_eq!(store.head_header().unwrap().hash(), b1.hash());
+	}
+
+	#[test]
+	fn incremental_total_difficulty() {
+		let dir = TempDir::new("grin_total_diff");
+		let store = ChainKVStore::new(dir.path()).unwrap();
+		let gen = core::genesis::genesis();
+		let mut tip = store.init(&gen).unwrap();
+		let mut prev = gen.hash();
+		for n in 1..21 {
+			let mut b = core::genesis::genesis();
+			b.header.height = n;
+			b.header.previous = prev;
+			// varying difficulties
+			b.header.target.0[0] >>= n % 4;
+			store.save_block(&b).unwrap();
+			tip = tip.append(&b.header).unwrap();
+			store.save_head(&tip).unwrap();
+			prev = b.hash();
+		}
+		assert_eq!(tip.height, 20);
+		tip.verify_total_difficulty(&store).unwrap();
+		store.verify_chain(0).unwrap();
+
+		let off = Tip { total_difficulty: Difficulty(tip.total_difficulty.0 + 1), ..tip };
+		assert!(off.verify_total_difficulty(&store).is_err());
+	}
+
+	#[test]
+	fn fallback_height_index() {
+		let dir = TempDir::new("grin_height_index");
+		let store = ChainKVStore::new(dir.path()).unwrap();
+		let gen = core::genesis::genesis();
+		let mut tip = store.init(&gen).unwrap();
+		let mut hashes = vec![gen.hash()];
+		for n in 1..6 {
+			let mut b = core::genesis::genesis();
+			b.header.height = n;
+			b.header.previous = hashes[n as usize - 1];
+			store.save_block(&b).unwrap();
+			tip = tip.append(&b.header).unwrap();
+			store.save_head(&tip).unwrap();
+			hashes.push(b.hash());
+		}
+
+		// missing entry, and one pointing at the wrong height
+		store.delete(&height_key(2)).unwrap();
+		store.put(&height_key(3), hashes[1].to_vec()).unwrap();
+		assert!(store.verify_chain(0).is_err());
+		assert_eq!(store.get_header_by_height(2).unwrap().hash(), hashes[2]);
+		assert_eq!(store.get_header_by_height(3).unwrap().hash(), hashes[3]);
+		assert!(store.get_header_by_height(6).is_err());
+
+		// leftover above the head
+		store.put(&height_key(6), hashes[5].to_vec()).unwrap();
+		assert_eq!(store.rebuild_height_index().unwrap(), 6);
+		store.verify_chain(0).unwrap();
+		assert!(store.get_raw(&height_key(6)).unwrap().is_none());
+	}
+
+	#[test]
+	fn isolate_chains() {
+		let dir = TempDir::new("grin_chains");
+		let store = ChainKVStore::new(dir.path()).unwrap();
+		let (main, test) = (store.chain(ChainId(1)), store.chain(ChainId(2)));
+		let gen = core::genesis::genesis();
+		main.init(&gen).unwrap();
+		test.init(&gen).unwrap();
+		assert!(store.head().is_err());
+
+		let mut b1 = core::genesis::genesis();
+		b1.header.height = 1;
+		b1.header.previous = gen.hash();
+		main.save_block(&b1).unwrap();
+		main.save_head(&main.head().unwrap().append(&b1.header).unwrap()).unwrap();
+		main.prune_bodies(1).unwrap();
+
+		assert_eq!(main.head().unwrap().height, 1);
+		assert_eq!(test.head().unwrap().height, 0);
+		assert!(test.get_block_header(&b1.hash()).is_err());
+		assert_eq!(test.body_horizon().unwrap(), 0);
+		assert_eq!(test.get_block(&gen.hash()).unwrap().hash(), gen.hash());
+		test.verify_chain(0).unwrap();
+	}
+
+	#[test]
+	fn detect_hash_collision() {
+		let dir = TempDir::new("grin_collision");
+		let store = ChainKVStore::new(dir.path()).unwrap();
+		let b = core::genesis::genesis();
+		store.save_block(&b).unwrap();
+		// saving the same block again is fine
+		store.save_block(&b).unwrap();
+
+		// tamper with the stored body so it doesn't match under the same hash
+		let key = to_key(BLOCK_PREFIX, &mut b.hash().to_vec()).clone();
+		let mut data = ser::ser_vec(&b).unwrap();
+		data.push(0);
+		store.db.put(&key[..], data).unwrap();
+		match store.save_block(&b) {
+			Err(Error::HashCollision(h)) => assert_eq!(h, b.hash()),
+			_ => panic!("should detect the hash collision"),
+		}
+	}
+
+	#[test]
+	fn limit_tracked_forks() {
+		let dir = TempDir::new("grin_forks");
+		let store = ChainKVStore::new(dir.path()).unwrap().with_max_tracked_forks(2);
+		let gen = core::genesis::genesis();
+		let mut tip = store.init(&gen).unwrap();
+		let mut main = vec![gen];
+		for n in 1..6 {
+			let b = child_of(&main[n - 1], 0);
+			store.save_block(&b).unwrap();
+			tip = tip.append(&b.header).unwrap();
+			store.save_head(&tip).unwrap();
+			main.push(b);
+		}
+		// everything up to 4 is final
+		store.add_difficulty_checkpoint(3, Difficulty(0)).unwrap();
+		assert_eq!(store.finalized_height().unwrap(), 4);
+		let fork_tip = |branch: u32, from: &Block, b: &Block, across: &[&Block]| {
+			let mut t = Tip::genesis(&main[0]);
+			t.height = from.header.height;
+			t.last_block_h = from.hash();
+			for a in across {
+				t = t.append(&a.header).unwrap();
+			}
+			t = t.append(&b.header).unwrap();
+			t.lineage = Lineage::new().fork(branch);
+			t
+		};
+
+		// b shares a1, which stays when a gets dropped
+		let a1 = child_of(&main[0], 1);
+		store.save_block(&a1).unwrap();
+		let tip_a = fork_tip(1, &main[0], &a1, &[]);
+		store.save_tip(&tip_a).unwrap();
+		assert_eq!(store.tracked_fork_count().unwrap(), 2);
+		let b2 = child_of(&a1, 2);
+		store.save_block(&b2).unwrap();
+		let tip_b = fork_tip(2, &main[0], &b2, &[&a1]);
+		store.save_tip(&tip_b).unwrap();
+		assert_eq!(store.tracked_fork_count().unwrap(), 2);
+		assert!(store.tip_for_block(&a1.hash()).is_err());
+		assert!(store.get_block(&a1.hash()).is_ok());
+
+		// the lighter c saved last stays, b goes with its blocks
+		let c1 = child_of(&main[0], 3);
+		store.save_block(&c1).unwrap();
+		store.save_tip(&fork_tip(3, &main[0], &c1, &[])).unwrap();
+		assert_eq!(store.tracked_fork_count().unwrap(), 2);
+		assert!(store.tip_for_block(&b2.hash()).is_err());
+		assert!(store.get_block(&b2.hash()).is_err());
+		assert!(store.get_block(&a1.hash()).is_err());
+		assert!(store.get_block(&c1.hash()).is_ok());
+
+		// forks above the finalized height are kept whatever the limit
+		let d5 = child_of(&main[4], 4);
+		store.save_block(&d5).unwrap();
+		store.save_tip(&fork_tip(4, &main[4], &d5, &[])).unwrap();
+		let e5 = child_of(&main[4], 5);
+		store.save_block(&e5).unwrap();
+		store.save_tip(&fork_tip(5, &main[4], &e5, &[])).unwrap();
+		assert_eq!(store.tracked_fork_count().unwrap(), 3);
+		assert!(store.get_block(&c1.hash()).is_err());
+		assert_eq!(store.head().unwrap().last_block_h, tip.last_block_h);
+		store.verify_chain(0).unwrap();
+	}
+
+	#[test]
+	fn seen_blocks_filter() {
+		let dir = TempDir::new("grin_seen");
+		let store = ChainKVStore::new(dir.path()).unwrap();
+		let gen = core::genesis::genesis();
+		store.init(&gen).unwrap();
+		let b1 = child_of(&gen, 0);
+		store.save_block(&b1).unwrap();
+		// no filter, may have anything
+		assert!(store.probably_have(&child_of(&gen, 1).hash()));
+
+		// built from the blocks already there
+		let policy = SeenFilterPolicy {
+			expected_blocks: 1000,
+			false_positive_rate: 0.001,
+		};
+		let store = store.with_seen_filter(policy).unwrap();
+		assert!(store.probably_have(&gen.hash()));
+		assert!(store.probably_have(&b1.hash()));
+		let b2 = child_of(&b1, 0);
+		assert!(!store.probably_have(&b2.hash()));
+		store.save_block(&b2).unwrap();
+		assert!(store.probably_have(&b2.hash()));
+	}
+
+	#[test]
+	fn median_time_past() {
+		let dir = TempDir::new("grin_median_time");
+		let store = ChainKVStore::new(dir.path()).unwrap();
+		let gen = core::genesis::genesis();
+		let mut tip = store.init(&gen).unwrap();
+		let gen_ts = gen.header.timestamp.to_timespec().sec as u64;
+		assert_eq!(store.min_next_timestamp(&tip).unwrap(), gen_ts + 1);
+
+		// timestamps out of order, the median is what matters
+		let mut main = vec![gen];
+		for &offset in &[30, 40, 10] {
+			let mut b = child_of(main.last().unwrap(), 0);
+			b.header.timestamp = b.header.timestamp + time::Duration::seconds(offset);
+			store.save_block(&b).unwrap();
+			tip = tip.append(&b.header).unwrap();
+			store.save_head(&tip).unwrap();
+			main.push(b);
+		}
+		assert_eq!(store.min_next_timestamp(&tip).unwrap(), gen_ts + 31);
+
+		// forks only look at their own blocks
+		let mut fork_b2 = child_of(&main[1], 1);
+		fork_b2.header.timestamp = fork_b2.header.timestamp + time::Duration::seconds(100);
+		store.save_block(&fork_b2).unwrap();
+		let fork = Tip::genesis(&main[0]).append(&main[1].header).unwrap().append(&fork_b2.header).unwrap();
+		assert_eq!(store.min_next_timestamp(&fork).unwrap(), gen_ts + 101);
+	}
+
+	#[test]
+	fn cache_main_chain_heights() {
+		let dir = TempDir::new("grin_height_cache");
+		let store = ChainKVStore::new(dir.path()).unwrap();
+		let gen = core::genesis::genesis();
+		let mut tip = store.init(&gen).unwrap();
+		let b1 = child_of(&gen, 0);
+		store.save_block(&b1).unwrap();
+		tip = tip.append(&b1.header).unwrap();
+		store.save_head(&tip).unwrap();
+		let fork_b1 = child_of(&gen, 1);
+		store.save_block(&fork_b1).unwrap();
+		let unknown = child_of(&b1, 0).hash();
+
+		// filled as blocks join the main chain, the others on lookup
+		assert_eq!(store.main_chain_height(&b1.hash()).unwrap(), Some(1));
+		assert_eq!(store.main_chain_height(&fork_b1.hash()).unwrap(), None);
+		assert_eq!(store.main_chain_height(&unknown).unwrap(), None);
+		assert_eq!(store.main_chain_height(&unknown).unwrap(), None);
+		let stats = store.height_cache_stats();
+		assert_eq!((stats.hits, stats.misses), (2, 2));
+
+		// a reorg updates the blocks switching sides
+		let fork_b2 = child_of(&fork_b1, 0);
+		store.save_block(&fork_b2).unwrap();
+		let fork = Tip::genesis(&gen).append(&fork_b1.header).unwrap().append(&fork_b2.header).unwrap();
+		store.save_head(&fork).unwrap();
+		assert_eq!(store.main_chain_height(&b1.hash()).unwrap(), None);
+		assert_eq!(store.main_chain_height(&fork_b1.hash()).unwrap(), Some(1));
+		assert_eq!(store.main_chain_height(&fork_b2.hash()).unwrap(), Some(2));
+		assert_eq!(store.height_cache_stats().hits, 5);
+
+		let store = store.with_height_cache(0);
+		assert_eq!(store.main_chain_height(&fork_b2.hash()).unwrap(), Some(2));
+		assert_eq!(store.height_cache_stats().len, 0);
+	}
+
+	#[test]
+	fn cache_common_ancestors() {
+		let dir = TempDir::new("grin_ancestor_cache");
+		let store = ChainKVStore::new(dir.path()).unwrap();
+		let gen = core::genesis::genesis();
+		let head = store.init(&gen).unwrap();
+		let b1 = child_of(&gen, 0);
+		store.save_block(&b1).unwrap();
+		let fork = Tip { lineage: head.lineage.fork(1), ..head.append(&b1.header).unwrap() };
+		store.save_tip(&fork).unwrap();
+
+		assert_eq!(store.common_ancestor(&head, &fork), 0);
+		assert_eq!(store.common_ancestor(&fork, &head), 0);
+		let stats = store.ancestor_cache_stats();
+		assert_eq!((stats.hits, stats.misses, stats.len), (1, 1, 1));
+
+		// the pairs of a deleted tip go with it
+		store.delete_tip(&fork).unwrap();
+		assert_eq!(store.ancestor_cache_stats().len, 0);
+	}
+
+	#[test]
+	fn import_metadata_over_checkpoints() {
+		let dir = TempDir::new("grin_import_metadata");
+		let store = ChainKVStore::new(dir.path()).unwrap();
+		let gen = core::genesis::genesis();
+		let tip = store.init(&gen).unwrap();
+		store.add_difficulty_checkpoint(0, tip.total_difficulty).unwrap();
+		let mut backup = vec![];
+		store.export_metadata(&mut backup).unwrap();
+		let checkpoints = store.difficulty_checkpoints().unwrap();
+
+		// checkpoints added or changed since the export don't survive it
+		store.add_difficulty_checkpoint(0, Difficulty(tip.total_difficulty.0 + 1)).unwrap();
+		store.add_difficulty_checkpoint(5, Difficulty(100)).unwrap();
+		store.import_metadata(&backup[..]).unwrap();
+		assert_eq!(store.difficulty_checkpoints().unwrap(), checkpoints);
+		assert_eq!(store.get_difficulty_checkpoint(0).unwrap(), tip.total_difficulty);
+		assert!(store.get_difficulty_checkpoint(5).is_err());
+		assert_eq!(store.head().unwrap().last_block_h, gen.hash());
+	}
+
+	#[test]
+	fn restore_state_snapshot() {
+		let dir = TempDir::new("grin_state_snapshot");
+		let store = ChainKVStore::new(dir.path()).unwrap();
+		let gen = core::genesis::genesis();
+		let tip = store.init(&gen).unwrap();
+		let state = store.state_snapshot().unwrap();
+		let events = store.events_since(0).unwrap().len();
+
+		// a new head moves the index, tips, header tip and event log
+		let b1 = child_of(&gen, 0);
+		store.save_block(&b1).unwrap();
+		let head = tip.append(&b1.header).unwrap();
+		store.save_head(&head).unwrap();
+		store.save_header_tip(&HeaderTip::from_tip(&head)).unwrap();
+		assert_eq!(store.main_chain_height(&b1.hash()).unwrap(), Some(1));
+
+		store.restore_state(&state).unwrap();
+		assert_eq!(store.head().unwrap().last_block_h, gen.hash());
+		assert_eq!(store.best_header_tip().unwrap().last_block_h, gen.hash());
+		assert_eq!(store.get_tips().unwrap().len(), 1);
+		assert!(store.get_header_by_height(1).is_err());
+		assert_eq!(store.main_chain_height(&b1.hash()).unwrap(), None);
+		assert_eq!(store.events_since(0).unwrap().len(), events);
+		// blocks are left alone
+		assert!(store.get_block(&b1.hash()).is_ok());
+	}
+
+	// Block on top of the provided one, without proof of work, the nonce
+	// telling siblings apart
+	fn child_of(prev: &Block, nonce: u64) -> Block {
+		let mut b = core::genesis::genesis();
+		b.header.height = prev.header.height + 1;
+		b.header.previous = prev.hash();
+		b.header.nonce = nonce;
+		b
+	}
+}
+
+#[cfg(all(test, feature = "unstable"))]
+mod benches {
+	use test::{Bencher, black_box};
+
+	use super::*;
+
+	// Number of headers read, a difficulty window's worth
+	const WINDOW: usize = 1000;
+
+	// Store with a chain of WINDOW headers above genesis, returning the hash
+	// of the last one
+	fn header_chain(dir: &TempDir) -> (ChainKVStore, Hash) {
+		let store = ChainKVStore::new(dir.path()).unwrap();
+		let mut prev = core::genesis::genesis().header;
+		store.save_block_header(&prev).unwrap();
+		for _ in 0..WINDOW {
+			let mut bh = core::genesis::genesis().header;
+			bh.height = prev.height + 1;
+			bh.previous = prev.hash();
+			store.save_block_header(&bh).unwrap();
+			prev = bh;
+		}
+		(store, prev.hash())
+	}
+
+	#[bench]
+	fn window_read(bh: &mut Bencher) {
+		let dir = TempDir::new("grin_bench_window");
+		let (store, last) = header_chain(&dir);
+		bh.iter(|| {
+			let mut h = last;
+			for _ in 0..WINDOW {
+				let header = store.get_block_header(&h).unwrap();
+				h = header.previous;
+				black_box(header);
+			}
+		});
+	}
+
+	#[bench]
+	fn window_read_buf(bh: &mut Bencher) {
+		let dir = TempDir::new("grin_bench_window_buf");
+		let (store, last) = header_chain(&dir);
+		bh.iter(|| {
+			for header in HeaderRevIter::new(&store, Ok(last)).take(WINDOW) {
+				black_box(header.unwrap());
+			}
+		});
+	}
+}
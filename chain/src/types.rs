@@ -14,9 +14,37 @@
 
 //! Base types that the block chain pipeline requires.
 
-use core::core::hash::Hash;
-use core::core::{Block, BlockHeader};
-use core::ser;
+use std::cmp;
+use std::collections::HashSet;
+use std::sync::mpsc::Receiver;
+
+use core::consensus;
+use core::genesis;
+use core::core::hash::{Hash, Hashed};
+use core::core::target::{Difficulty, Target};
+use core::core::{Block, BlockHeader, TxProof};
+use core::ser::{self, Readable, Writeable};
+use heights::CacheStats;
+
+/// Number of seconds without the head moving after which we consider the
+/// sync to be stalled. Same 12 block intervals we tolerate for future blocks.
+pub const SYNC_STALL_SECS: u64 = 12 * (consensus::BLOCK_TIME_SEC as u64);
+
+/// Maximum number of blocks a chain diff can contain on each side before we
+/// consider a full resync necessary.
+pub const MAX_CHAIN_DIFF: usize = 1000;
+
+/// Number of blocks under the head after which a block is considered final,
+/// reorging deeper than a chain diff can hold requiring a full resync anyway.
+pub const FINALITY_DEPTH: u64 = MAX_CHAIN_DIFF as u64;
+
+/// Number of last blocks whose median timestamp a child block must be above.
+pub const MEDIAN_TIME_WINDOW: usize = 11;
+
+/// Identifier of one of the chains hosted in a store, like a network id, to
+/// keep several chains (mainnet, testnet) in the same db.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ChainId(pub u32);
 
 /// The lineage of a fork, defined as a series of numbers. Each new branch gets
 /// a new number that gets added to a fork's ancestry to form a new fork.
@@ -37,6 +65,31 @@ impl Lineage {
 	pub fn last_branch(&self) -> u32 {
 		*self.0.last().unwrap()
 	}
+	/// Number of branches in the lineage, one more than the number of forks
+	/// it went through.
+	pub fn depth(&self) -> usize {
+		self.0.len()
+	}
+	/// New lineage forking from this one with the provided branch number.
+	pub fn fork(&self, branch: u32) -> Lineage {
+		let mut branches = self.0.clone();
+		branches.push(branch);
+		Lineage(branches)
+	}
+	/// The last branch shared by this lineage and the other one, where the
+	/// two forks diverge. Both lineages start from branch 0 so there's always
+	/// at least that one in common. Walks both lineages, see AncestorCache
+	/// to avoid doing it for every competing block.
+	pub fn common_ancestor(&self, other: &Lineage) -> u32 {
+		let mut ancestor = 0;
+		for (a, b) in self.0.iter().zip(other.0.iter()) {
+			if a != b {
+				break;
+			}
+			ancestor = *a;
+		}
+		ancestor
+	}
 }
 
 /// Serialization for lineage, necessary to serialize fork tips.
@@ -72,53 +125,590 @@ pub struct Tip {
 	pub last_block_h: Hash,
 	/// Block previous to last
 	pub prev_block_h: Hash,
+	/// Total difficulty accumulated on the fork, including the genesis block
+	/// own difficulty. A tip at height 0 has the genesis difficulty.
+	pub total_difficulty: Difficulty,
 	/// Lineage in branch numbers of the fork
 	pub lineage: Lineage,
 }
 
 impl Tip {
-	/// Creates a new tip at height zero and the provided genesis hash.
-	pub fn new(gbh: Hash) -> Tip {
+	/// Creates a new tip at height zero for the provided genesis block,
+	/// seeding the total difficulty with the genesis block difficulty.
+	pub fn genesis(gen: &Block) -> Tip {
+		let gbh = gen.hash();
 		Tip {
 			height: 0,
 			last_block_h: gbh,
 			prev_block_h: gbh,
+			total_difficulty: Difficulty::from_target(&gen.header.target),
 			lineage: Lineage::new(),
 		}
 	}
 
-	/// Append a new block hash to this tip, returning a new updated tip.
-	pub fn append(&self, bh: Hash) -> Tip {
-		Tip {
-			height: self.height + 1,
-			last_block_h: bh,
+	/// Append a new block to this tip, returning a new updated tip. The total
+	/// difficulty is only updated with the block own difficulty, never
+	/// recomputed from the whole fork. Fails if the block doesn't declare the
+	/// height right above the tip or if the total difficulty would overflow.
+	pub fn append(&self, bh: &BlockHeader) -> Result<Tip, Error> {
+		if bh.height != self.height + 1 {
+			return Err(Error::NonContiguousHeight {
+				expected: self.height + 1,
+				got: bh.height,
+			});
+		}
+		let total_difficulty = try!(add_difficulty(self.total_difficulty, bh));
+		Ok(Tip {
+			height: bh.height,
+			last_block_h: bh.hash(),
 			prev_block_h: self.last_block_h,
+			total_difficulty: total_difficulty,
 			lineage: self.lineage.clone(),
+		})
+	}
+
+	/// Hash of the serialized lineage, a compact fixed-size identifier for the
+	/// fork this tip is on. Tips on the same branch share a lineage hash.
+	pub fn lineage_hash(&self) -> Hash {
+		self.lineage.hash()
+	}
+
+	/// Walks the fork down to genesis to check the total difficulty
+	/// accumulated block after block is the sum of the fork blocks
+	/// difficulties. Expensive, for tests and chain verification only.
+	pub fn verify_total_difficulty(&self, store: &ChainStore) -> Result<(), Error> {
+		let mut total = Difficulty(0);
+		for bh in HeaderRevIter::new(store, Ok(self.last_block_h)) {
+			total = try!(add_difficulty(total, &try!(bh)));
+		}
+		if total != self.total_difficulty {
+			return Err(Error::InconsistentChain(self.height,
+			                                    format!("total difficulty {} doesn't match the {} of \
+			                                             its blocks",
+			                                            self.total_difficulty,
+			                                            total)));
 		}
+		Ok(())
+	}
+
+	/// Checks the tip is consistent with its lineage. Each fork in the
+	/// lineage happens at a different height so a tip can't have more
+	/// branches than blocks.
+	pub fn validate(&self) -> Result<(), Error> {
+		if self.lineage.depth() == 0 || self.height + 1 < self.lineage.depth() as u64 {
+			return Err(Error::StorageErr(StorageError::Corruption("corrupt tip".to_string())));
+		}
+		Ok(())
 	}
 }
 
-/// Serialization of a tip, required to save to datastore.
+bitflags! {
+  /// Optional fields present in a serialized tip, written after the
+  /// mandatory ones in the order of their flag bits.
+  pub flags TipFields: u16 {
+    /// Total difficulty of the fork
+    const TIP_TOTAL_DIFFICULTY = 0b00000001,
+  }
+}
+
+/// Serialization of a tip, required to save to datastore. The height comes
+/// first, followed by the flags of the optional fields, the mandatory fields
+/// and finally the optional ones. Readers ignore the flags they don't know
+/// about, the corresponding fields being last.
 impl ser::Writeable for Tip {
 	fn write(&self, writer: &mut ser::Writer) -> Result<(), ser::Error> {
 		try!(writer.write_u64(self.height));
+		try!(writer.write_u16(TIP_TOTAL_DIFFICULTY.bits()));
 		try!(writer.write_fixed_bytes(&self.last_block_h));
 		try!(writer.write_fixed_bytes(&self.prev_block_h));
-		self.lineage.write(writer)
+		try!(self.lineage.write(writer));
+		self.total_difficulty.write(writer)
 	}
 }
 
 impl ser::Readable<Tip> for Tip {
 	fn read(reader: &mut ser::Reader) -> Result<Tip, ser::Error> {
 		let height = try!(reader.read_u64());
+		let fields = TipFields::from_bits_truncate(try!(reader.read_u16()));
 		let last = try!(Hash::read(reader));
 		let prev = try!(Hash::read(reader));
 		let line = try!(Lineage::read(reader));
-		Ok(Tip {
+		let diff = if fields.contains(TIP_TOTAL_DIFFICULTY) {
+			try!(Difficulty::read(reader))
+		} else {
+			Difficulty(0)
+		};
+		let tip = Tip {
 			height: height,
 			last_block_h: last,
 			prev_block_h: prev,
+			total_difficulty: diff,
 			lineage: line,
+		};
+		try!(tip.validate().map_err(|_| ser::Error::CorruptedData));
+		Ok(tip)
+	}
+}
+
+/// The tip of the most worked header chain, regardless of which block bodies
+/// we have. Used for header first sync, the bodies to download being the ones
+/// between the head and the header tip.
+#[derive(Debug, Clone)]
+pub struct HeaderTip {
+	/// Height of the last header
+	pub height: u64,
+	/// Hash of the last header
+	pub last_block_h: Hash,
+	/// Hash of the header previous to last
+	pub prev_block_h: Hash,
+	/// Total difficulty accumulated on the header chain
+	pub total_difficulty: Difficulty,
+}
+
+impl HeaderTip {
+	/// Header tip at the same block as the provided tip.
+	pub fn from_tip(t: &Tip) -> HeaderTip {
+		HeaderTip {
+			height: t.height,
+			last_block_h: t.last_block_h,
+			prev_block_h: t.prev_block_h,
+			total_difficulty: t.total_difficulty,
+		}
+	}
+
+	/// Append a new header to this header tip, returning a new updated one.
+	/// Fails if the total difficulty would overflow.
+	pub fn append(&self, bh: &BlockHeader) -> Result<HeaderTip, Error> {
+		Ok(HeaderTip {
+			height: self.height + 1,
+			last_block_h: bh.hash(),
+			prev_block_h: self.last_block_h,
+			total_difficulty: try!(add_difficulty(self.total_difficulty, bh)),
+		})
+	}
+}
+
+/// Total difficulty after adding the difficulty of the provided block,
+/// refusing to wrap around as a wrapped total would make a fork lighter than
+/// it is.
+pub fn add_difficulty(total: Difficulty, bh: &BlockHeader) -> Result<Difficulty, Error> {
+	total.checked_add(Difficulty::from_target(&bh.target))
+		.ok_or(Error::DifficultyOverflow(bh.height))
+}
+
+/// Total difficulty after taking the difficulty of the provided block back
+/// out. Going below zero means the total doesn't account for the block, as
+/// with a tip saved before tips carried their total difficulty.
+pub fn sub_difficulty(total: Difficulty, bh: &BlockHeader) -> Result<Difficulty, Error> {
+	total.checked_sub(Difficulty::from_target(&bh.target)).ok_or_else(|| {
+		Error::InconsistentChain(bh.height,
+		                         format!("total difficulty {} lower than the block's", total))
+	})
+}
+
+impl ser::Writeable for HeaderTip {
+	fn write(&self, writer: &mut ser::Writer) -> Result<(), ser::Error> {
+		try!(writer.write_u64(self.height));
+		try!(writer.write_fixed_bytes(&self.last_block_h));
+		try!(writer.write_fixed_bytes(&self.prev_block_h));
+		self.total_difficulty.write(writer)
+	}
+}
+
+impl ser::Readable<HeaderTip> for HeaderTip {
+	fn read(reader: &mut ser::Reader) -> Result<HeaderTip, ser::Error> {
+		let height = try!(reader.read_u64());
+		let last = try!(Hash::read(reader));
+		let prev = try!(Hash::read(reader));
+		let diff = try!(Difficulty::read(reader));
+		Ok(HeaderTip {
+			height: height,
+			last_block_h: last,
+			prev_block_h: prev,
+			total_difficulty: diff,
+		})
+	}
+}
+
+/// Progress of the chain head over a period of time, used to detect a
+/// stalled sync.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyncProgress {
+	/// Number of blocks the head advanced since the baseline
+	pub blocks: u64,
+	/// Average number of blocks added per second since the baseline
+	pub blocks_per_sec: f64,
+	/// Whether the head hasn't moved for longer than SYNC_STALL_SECS
+	pub stalled: bool,
+}
+
+impl SyncProgress {
+	/// Computes the progress between a baseline height and time and the
+	/// current height and time.
+	pub fn new(baseline_height: u64,
+	           baseline_time: u64,
+	           height: u64,
+	           now: u64)
+	           -> SyncProgress {
+		let blocks = height.saturating_sub(baseline_height);
+		let elapsed = now.saturating_sub(baseline_time);
+		let bps = if elapsed > 0 {
+			blocks as f64 / elapsed as f64
+		} else {
+			0.0
+		};
+		SyncProgress {
+			blocks: blocks,
+			blocks_per_sec: bps,
+			stalled: blocks == 0 && elapsed >= SYNC_STALL_SECS,
+		}
+	}
+}
+
+/// Difference between our chain and a peer's, starting from the last block
+/// both chains have in common.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainDiff {
+	/// Hashes of the blocks the peer is missing to reach our head, in the
+	/// order they should be connected
+	pub missing: Vec<Hash>,
+	/// Hashes of the blocks on the peer's fork it would have to disconnect,
+	/// starting from its tip
+	pub disconnect: Vec<Hash>,
+}
+
+/// Whether a block joined or left the main chain.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChainEventKind {
+	/// The block was added to the main chain
+	Connected,
+	/// The block was removed from the main chain by a reorg
+	Disconnected,
+	/// The head was manually forced to the block, overriding fork choice
+	Forced,
+}
+
+/// Entry of the chain event log, recording a change of the main chain.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainEvent {
+	/// Sequence number of the event in the log, increasing by one
+	pub seq: u64,
+	/// What happened to the block
+	pub kind: ChainEventKind,
+	/// Hash of the block
+	pub hash: Hash,
+	/// Height of the block
+	pub height: u64,
+}
+
+impl ser::Writeable for ChainEvent {
+	fn write(&self, writer: &mut ser::Writer) -> Result<(), ser::Error> {
+		try!(writer.write_u64(self.seq));
+		try!(writer.write_u8(match self.kind {
+			ChainEventKind::Connected => 0,
+			ChainEventKind::Disconnected => 1,
+			ChainEventKind::Forced => 2,
+		}));
+		try!(writer.write_fixed_bytes(&self.hash));
+		writer.write_u64(self.height)
+	}
+}
+
+impl ser::Readable<ChainEvent> for ChainEvent {
+	fn read(reader: &mut ser::Reader) -> Result<ChainEvent, ser::Error> {
+		let seq = try!(reader.read_u64());
+		let kind = match try!(reader.read_u8()) {
+			0 => ChainEventKind::Connected,
+			1 => ChainEventKind::Disconnected,
+			2 => ChainEventKind::Forced,
+			_ => return Err(ser::Error::CorruptedData),
+		};
+		let hash = try!(Hash::read(reader));
+		let height = try!(reader.read_u64());
+		Ok(ChainEvent {
+			seq: seq,
+			kind: kind,
+			hash: hash,
+			height: height,
+		})
+	}
+}
+
+/// Maximum number of blocks in a locator, way more than the exponential
+/// spacing needs for any realistic chain length.
+pub const MAX_LOCATOR_LEN: usize = 64;
+
+/// Main chain blocks going back from the head, the first ones contiguous then
+/// exponentially spaced, and ending with genesis. Lets a peer find the last
+/// block we have in common in a few lookups.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockLocator {
+	/// Hashes of the blocks, from the head down
+	pub hashes: Vec<Hash>,
+	/// Heights of the blocks, matching the hashes
+	pub heights: Vec<u64>,
+}
+
+impl ser::Writeable for BlockLocator {
+	fn write(&self, writer: &mut ser::Writer) -> Result<(), ser::Error> {
+		if self.hashes.len() > MAX_LOCATOR_LEN || self.hashes.len() != self.heights.len() {
+			return Err(ser::Error::CorruptedData);
+		}
+		try!(writer.write_u8(self.hashes.len() as u8));
+		for (h, height) in self.hashes.iter().zip(self.heights.iter()) {
+			try!(writer.write_fixed_bytes(h));
+			try!(writer.write_u64(*height));
+		}
+		Ok(())
+	}
+}
+
+impl ser::Readable<BlockLocator> for BlockLocator {
+	fn read(reader: &mut ser::Reader) -> Result<BlockLocator, ser::Error> {
+		let len = try!(reader.read_u8()) as usize;
+		if len > MAX_LOCATOR_LEN {
+			return Err(ser::Error::TooLargeReadErr);
+		}
+		let mut hashes = Vec::with_capacity(len);
+		let mut heights = Vec::with_capacity(len);
+		for _ in 0..len {
+			hashes.push(try!(Hash::read(reader)));
+			heights.push(try!(reader.read_u64()));
+		}
+		Ok(BlockLocator {
+			hashes: hashes,
+			heights: heights,
+		})
+	}
+}
+
+/// Maximum number of blocks a main chain proof can go under the head. At the
+/// finality depth, a block can't be reorged away anymore anyway.
+pub const MAX_PROOF_DEPTH: u64 = FINALITY_DEPTH;
+
+/// Proof that a block is on the main chain ending at a given head, for light
+/// clients. Headers only link to their previous one, so the proof is the
+/// header of the block followed by all the main chain headers up to the
+/// head. It is NOT compact: its size is linear in the depth of the block,
+/// one full header per block, up to MAX_PROOF_DEPTH blocks deep.
+pub struct MainChainProof {
+	/// Headers from the block to the head, by increasing height
+	pub headers: Vec<BlockHeader>,
+}
+
+impl MainChainProof {
+	/// Whether the proof links the block to the provided trusted head, each
+	/// header pointing to the previous one.
+	pub fn verify(&self, trusted_head: &Hash) -> bool {
+		if self.headers.len() as u64 > MAX_PROOF_DEPTH + 1 {
+			return false;
+		}
+		let last = match self.headers.last() {
+			Some(last) => last,
+			None => return false,
+		};
+		for pair in self.headers.windows(2) {
+			if pair[1].previous != pair[0].hash() || pair[1].height != pair[0].height + 1 {
+				return false;
+			}
+		}
+		last.hash() == *trusted_head
+	}
+
+	/// Hash of the block the proof is about.
+	pub fn block_hash(&self) -> Option<Hash> {
+		self.headers.first().map(|bh| bh.hash())
+	}
+}
+
+impl ser::Writeable for MainChainProof {
+	fn write(&self, writer: &mut ser::Writer) -> Result<(), ser::Error> {
+		try!(writer.write_u64(self.headers.len() as u64));
+		for bh in &self.headers {
+			try!(bh.write(writer));
+		}
+		Ok(())
+	}
+}
+
+impl ser::Readable<MainChainProof> for MainChainProof {
+	fn read(reader: &mut ser::Reader) -> Result<MainChainProof, ser::Error> {
+		let len = try!(reader.read_u64());
+		if len > MAX_PROOF_DEPTH + 1 {
+			return Err(ser::Error::TooLargeReadErr);
+		}
+		let mut headers = Vec::with_capacity(len as usize);
+		for _ in 0..len {
+			headers.push(try!(BlockHeader::read(reader)));
+		}
+		Ok(MainChainProof { headers: headers })
+	}
+}
+
+/// Total difficulty the main chain is expected to have at a given height.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DifficultyCheckpoint {
+	/// Height of the checkpoint
+	pub height: u64,
+	/// Total difficulty expected at that height
+	pub total_difficulty: Difficulty,
+}
+
+impl ser::Writeable for DifficultyCheckpoint {
+	fn write(&self, writer: &mut ser::Writer) -> Result<(), ser::Error> {
+		try!(writer.write_u64(self.height));
+		self.total_difficulty.write(writer)
+	}
+}
+
+impl ser::Readable<DifficultyCheckpoint> for DifficultyCheckpoint {
+	fn read(reader: &mut ser::Reader) -> Result<DifficultyCheckpoint, ser::Error> {
+		let height = try!(reader.read_u64());
+		let diff = try!(Difficulty::read(reader));
+		Ok(DifficultyCheckpoint {
+			height: height,
+			total_difficulty: diff,
+		})
+	}
+}
+
+/// Raw entries of the chain state, see ChainStore::state_snapshot, as laid
+/// out by the store that took it.
+pub struct StateSnapshot {
+	/// Keys and values of the state entries
+	pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Version of the chain metadata serialization, bumped when fields are added
+/// so newer versions can still read older backups.
+pub const CHAIN_METADATA_VERSION: u8 = 1;
+
+/// Snapshot of the chain metadata, everything but the block data itself,
+/// for cheap backups.
+#[derive(Debug, Clone)]
+pub struct ChainMetadata {
+	/// The main chain head
+	pub head: Tip,
+	/// All the fork tips, including the head one
+	pub tips: Vec<Tip>,
+	/// Tip of the header chain
+	pub header_tip: HeaderTip,
+	/// Total difficulty checkpoints
+	pub difficulty_checkpoints: Vec<DifficultyCheckpoint>,
+	/// Height below which block bodies were pruned
+	pub body_horizon: u64,
+}
+
+impl ser::Writeable for ChainMetadata {
+	fn write(&self, writer: &mut ser::Writer) -> Result<(), ser::Error> {
+		try!(writer.write_u8(CHAIN_METADATA_VERSION));
+		try!(self.head.write(writer));
+		try!(writer.write_u64(self.tips.len() as u64));
+		for t in &self.tips {
+			try!(t.write(writer));
+		}
+		try!(self.header_tip.write(writer));
+		try!(writer.write_u64(self.difficulty_checkpoints.len() as u64));
+		for c in &self.difficulty_checkpoints {
+			try!(c.write(writer));
+		}
+		writer.write_u64(self.body_horizon)
+	}
+}
+
+impl ser::Readable<ChainMetadata> for ChainMetadata {
+	fn read(reader: &mut ser::Reader) -> Result<ChainMetadata, ser::Error> {
+		let version = try!(reader.read_u8());
+		if version != CHAIN_METADATA_VERSION {
+			return Err(ser::Error::UnexpectedData {
+				expected: vec![CHAIN_METADATA_VERSION],
+				received: vec![version],
+			});
+		}
+		let head = try!(Tip::read(reader));
+		let tip_count = try!(reader.read_u64());
+		let mut tips = vec![];
+		for _ in 0..tip_count {
+			tips.push(try!(Tip::read(reader)));
+		}
+		let header_tip = try!(HeaderTip::read(reader));
+		let checkpoint_count = try!(reader.read_u64());
+		let mut checkpoints = vec![];
+		for _ in 0..checkpoint_count {
+			checkpoints.push(try!(DifficultyCheckpoint::read(reader)));
+		}
+		let horizon = try!(reader.read_u64());
+		Ok(ChainMetadata {
+			head: head,
+			tips: tips,
+			header_tip: header_tip,
+			difficulty_checkpoints: checkpoints,
+			body_horizon: horizon,
+		})
+	}
+}
+
+/// Transactions a pool has to reconcile with after a reorg. Blocks only keep
+/// the proofs of the transactions they include, which is what the pool
+/// matches its transactions against.
+#[derive(Debug, Clone)]
+pub struct ReorgTxSet {
+	/// Proofs of the transactions from the disconnected blocks that aren't on
+	/// the new main chain, to add back to the pool
+	pub to_readd: Vec<TxProof>,
+	/// Proofs of the transactions from the connected blocks, to remove from
+	/// the pool
+	pub to_remove: Vec<TxProof>,
+}
+
+impl ReorgTxSet {
+	/// Builds the transaction set from the blocks leaving and joining the main
+	/// chain. A transaction on both sides ends up in neither set. Proofs
+	/// without fees are skipped, the coinbase ones being among them (a pool
+	/// wouldn't hold a transaction paying no fee anyway).
+	pub fn from_blocks(disconnected: &[Block], connected: &[Block]) -> ReorgTxSet {
+		let proofs = |blocks: &[Block]| {
+			let mut seen = HashSet::new();
+			blocks.iter()
+				.flat_map(|b| b.proofs.iter())
+				.filter(|p| p.fee > 0 && seen.insert(p.hash()))
+				.cloned()
+				.collect::<Vec<TxProof>>()
+		};
+		let old = proofs(disconnected);
+		let new = proofs(connected);
+		let old_hs: HashSet<Hash> = old.iter().map(|p| p.hash()).collect();
+		let new_hs: HashSet<Hash> = new.iter().map(|p| p.hash()).collect();
+		ReorgTxSet {
+			to_readd: old.into_iter().filter(|p| !new_hs.contains(&p.hash())).collect(),
+			to_remove: new.into_iter().filter(|p| !old_hs.contains(&p.hash())).collect(),
+		}
+	}
+}
+
+/// An orphan block, one whose parent we don't have yet, along with the time
+/// at which we received it.
+pub struct Orphan {
+	/// The orphan block itself
+	pub block: Block,
+	/// When we received the block, in seconds since epoch
+	pub received: i64,
+}
+
+impl ser::Writeable for Orphan {
+	fn write(&self, writer: &mut ser::Writer) -> Result<(), ser::Error> {
+		try!(writer.write_i64(self.received));
+		self.block.write(writer)
+	}
+}
+
+impl ser::Readable<Orphan> for Orphan {
+	fn read(reader: &mut ser::Reader) -> Result<Orphan, ser::Error> {
+		let received = try!(reader.read_i64());
+		let block = try!(Block::read(reader));
+		Ok(Orphan {
+			block: block,
+			received: received,
 		})
 	}
 }
@@ -127,30 +717,399 @@ impl ser::Readable<Tip> for Tip {
 pub enum Error {
 	/// Couldn't find what we were looking for
 	NotFoundErr,
+	/// The chains differ by more than MAX_CHAIN_DIFF blocks
+	DiffTooLarge,
+	/// The stored chain isn't consistent at the provided height, for the given
+	/// reason
+	InconsistentChain(u64, String),
+	/// The head points to a block whose body isn't in store anymore, the
+	/// head should be repaired
+	HeadBodyMissing(Hash),
+	/// The block body at the provided height was pruned, being below the body
+	/// horizon
+	BlockPruned(u64),
+	/// A block with the provided hash is already stored but serializes to
+	/// different bytes than the one being saved
+	HashCollision(Hash),
+	/// The fork at the provided height starts below the finalized height and
+	/// can't be reorged to
+	ForkBelowFinalized(u64),
+	/// Adding the difficulty of the block at the provided height overflows
+	/// the total difficulty
+	DifficultyOverflow(u64),
+	/// The block is the provided number of blocks under the head, more than
+	/// MAX_PROOF_DEPTH, too deep for a main chain proof
+	ProofTooDeep(u64),
+	/// A block appended to a tip doesn't declare the height right above it
+	NonContiguousHeight {
+		/// Height following the tip
+		expected: u64,
+		/// Height the block header declares
+		got: u64,
+	},
+	/// A tip height disagrees with the height of its last block header
+	TipHeightMismatch {
+		/// Height of the tip
+		tip_height: u64,
+		/// Height of the tip last block header
+		header_height: u64,
+	},
+	/// The chain is frozen at the provided height, only with the testing
+	/// feature
+	ChainFrozen(u64),
 	/// Error generated by the underlying storage layer
-	StorageErr(String),
+	StorageErr(StorageError),
+}
+
+/// Errors from the underlying storage layer, telling the transient ones
+/// worth retrying apart from the fatal ones.
+#[derive(Debug)]
+pub enum StorageError {
+	/// Reading or writing the db failed, possibly only temporarily
+	Io(String),
+	/// The db or the data in it is corrupted
+	Corruption(String),
+	/// Data couldn't be serialized or deserialized
+	Serialization(String),
+	/// Any other db failure, such as an invalid argument or an unsupported
+	/// operation, that retrying won't fix
+	Other(String),
+}
+
+impl StorageError {
+	/// Whether the operation that produced the error may succeed if retried.
+	pub fn is_transient(&self) -> bool {
+		match *self {
+			StorageError::Io(_) => true,
+			_ => false,
+		}
+	}
 }
 
 /// Trait the chain pipeline requires an implementor for in order to process
 /// blocks.
 pub trait ChainStore: Send + Sync {
+	/// Initializes an empty store with the provided genesis block, which
+	/// becomes the head and header tip. Returns the genesis tip.
+	fn init(&self, gen: &Block) -> Result<Tip, Error>;
+
 	/// Get the tip that's also the head of the chain
 	fn head(&self) -> Result<Tip, Error>;
 
+	/// Cumulative work of the whole main chain, the head total difficulty
+	fn total_work(&self) -> Result<Difficulty, Error>;
+
+	/// Height of the head of the chain, cheaper than reading the whole head
+	fn head_height(&self) -> Result<u64, Error>;
+
 	/// Block header for the chain head
 	fn head_header(&self) -> Result<BlockHeader, Error>;
 
+	/// Gets the full block at the head of the chain. Fails with
+	/// HeadBodyMissing if we have the head header but not its body.
+	fn head_block(&self) -> Result<Block, Error>;
+
+	/// Resets the head to the nearest block on the main chain, going back from
+	/// the current head, for which we still have a body. Returns the new head.
+	fn repair_head(&self) -> Result<Tip, Error>;
+
+	/// Target and Cuckoo size a child of the provided tip, main chain or fork,
+	/// is required to have when built with the provided timestamp. Applies the
+	/// consensus retarget to the tip last block.
+	fn next_target(&self, tip: &Tip, ts: i64) -> Result<(Target, u8), Error>;
+
+	/// Earliest timestamp, in seconds, a child of the provided tip (main chain
+	/// or fork) can have: one above the median timestamp of the last
+	/// MEDIAN_TIME_WINDOW blocks of that fork, and never at or below the
+	/// timestamp of the tip last block as time must strictly progress.
+	fn min_next_timestamp(&self, tip: &Tip) -> Result<u64, Error>;
+
 	/// Gets a block header by hash
 	fn get_block_header(&self, h: &Hash) -> Result<BlockHeader, Error>;
 
-	/// Save the provided block in store
+	/// Same as get_block_header, reading through the provided scratch buffer
+	/// to save an allocation per header when reading many in a row.
+	fn get_block_header_buf(&self, h: &Hash, buf: &mut Vec<u8>) -> Result<BlockHeader, Error>;
+
+	/// Gets the header of the main chain block at the provided height
+	fn get_header_by_height(&self, height: u64) -> Result<BlockHeader, Error>;
+
+	/// Height of the highest main chain block with a timestamp lower or equal
+	/// to the provided one, in seconds since the unix epoch. Header timestamps
+	/// are converted from their `time::Tm` with `to_timespec`, the ones before
+	/// the epoch counting as 0. Binary searches the main chain so assumes
+	/// timestamps are increasing, which holds modulo the allowed skew. Not
+	/// found if even the genesis block is more recent.
+	fn find_height_by_timestamp(&self, ts: u64) -> Result<u64, Error>;
+
+	/// Iterates over the main chain headers from the head back to genesis,
+	/// one read per header. Follows the previous block links from the head at
+	/// the time of the call, so a reorg while iterating doesn't affect it.
+	fn iter_main_chain_rev<'a>(&'a self) -> Box<Iterator<Item = Result<BlockHeader, Error>> + 'a>;
+
+	/// Gets a full block by hash. Fails with BlockPruned if we dropped its body
+	/// being below the body horizon.
+	fn get_block(&self, h: &Hash) -> Result<Block, Error>;
+
+	/// Whether we have the provided block, counting the ones whose body got
+	/// pruned below the body horizon. Doesn't deserialize the block.
+	fn contains_block(&self, h: &Hash) -> Result<bool, Error>;
+
+	/// Fast check whether we may have the provided block, before asking
+	/// contains_block. False means we surely don't, true that we might (always
+	/// true without a filter of seen blocks).
+	fn probably_have(&self, h: &Hash) -> bool;
+
+	/// Height under which main chain block bodies have been pruned, 0 if we
+	/// have them all.
+	fn body_horizon(&self) -> Result<u64, Error>;
+
+	/// Deletes the bodies of the main chain blocks below the provided height,
+	/// keeping their headers, and moves the body horizon there.
+	fn prune_bodies(&self, height: u64) -> Result<(), Error>;
+
+	/// Save the provided block in store. Fails with a hash collision if a
+	/// different block with the same hash is already stored.
 	fn save_block(&self, b: &Block) -> Result<(), Error>;
 
-	/// Save the provided tip as the current head of our chain
+	/// Save the provided block header alone, without its body
+	fn save_block_header(&self, bh: &BlockHeader) -> Result<(), Error>;
+
+	/// Writes the blocks and headers buffered in memory, if any, to the
+	/// underlying db. Done automatically before moving any tip.
+	fn flush(&self) -> Result<(), Error>;
+
+	/// Deletes the block with the provided hash, both header and body
+	fn delete_block(&self, h: &Hash) -> Result<(), Error>;
+
+	/// Save the provided tip as the current head of our chain, updating the
+	/// main chain height index and the event log accordingly
 	fn save_head(&self, t: &Tip) -> Result<(), Error>;
 
+	/// Snapshot of the chain state, everything but the blocks: head, header
+	/// tip, tips, height index, stale blocks, event log and body horizon.
+	/// Linear in the chain length, meant for an empty or freshly initialized
+	/// store about to be written to in bulk.
+	fn state_snapshot(&self) -> Result<StateSnapshot, Error>;
+
+	/// Puts the chain state back as it was in the snapshot, dropping whatever
+	/// was written to it since. Blocks are left untouched.
+	fn restore_state(&self, s: &StateSnapshot) -> Result<(), Error>;
+
+	/// Main chain events recorded after the one with the provided sequence
+	/// number, in order. Starting from 0 gives the whole log.
+	fn events_since(&self, seq: u64) -> Result<Vec<ChainEvent>, Error>;
+
+	/// Deletes the events for the blocks below the provided height from the
+	/// log, meant for heights that can't be reorged anymore.
+	fn truncate_events(&self, height: u64) -> Result<(), Error>;
+
+	/// Blocks that were on the main chain at the provided height or above
+	/// and got reorged out, by increasing height. Blocks only ever seen on a
+	/// side fork aren't included.
+	fn get_stale_blocks(&self, from_height: u64) -> Result<Vec<Hash>, Error>;
+
+	/// Drops the records kept for reorgs (events, stale marks) below the
+	/// finalized height, returning that height.
+	fn prune_finalized(&self) -> Result<u64, Error>;
+
+	/// Subscribes to head changes, each new head being sent on the returned
+	/// channel once saved, reorgs included. A subscriber too slow to keep up
+	/// misses notifications instead of blocking head updates.
+	fn subscribe_head(&self) -> Receiver<Tip>;
+
 	/// Save the provided tip without setting it as head
 	fn save_tip(&self, t: &Tip) -> Result<(), Error>;
+
+	/// Deletes the provided tip, forgetting about its fork
+	fn delete_tip(&self, t: &Tip) -> Result<(), Error>;
+
+	/// Persists an orphan block so it survives a restart
+	fn save_orphan(&self, o: &Orphan) -> Result<(), Error>;
+
+	/// Deletes a persisted orphan block, once connected or evicted
+	fn delete_orphan(&self, h: &Hash) -> Result<(), Error>;
+
+	/// Loads the persisted orphans received less than ttl_secs ago, at most
+	/// max of them, the most recent first. Orphans exceeding those limits
+	/// are deleted.
+	fn load_orphans(&self, max: usize, ttl_secs: i64) -> Result<Vec<Orphan>, Error>;
+
+	/// The tip of the most worked header chain we know of
+	fn best_header_tip(&self) -> Result<HeaderTip, Error>;
+
+	/// Save the provided header tip as the most worked header chain
+	fn save_header_tip(&self, t: &HeaderTip) -> Result<(), Error>;
+
+	/// All the fork tips we know of, including the head
+	fn get_tips(&self) -> Result<Vec<Tip>, Error>;
+
+	/// Height of the provided block if it's on the main chain, None if it's
+	/// on a side chain or unknown. Answered from a cache of recent lookups,
+	/// unknown hashes included, kept up to date as blocks join or leave the
+	/// main chain.
+	fn main_chain_height(&self, h: &Hash) -> Result<Option<u64>, Error>;
+
+	/// Hits and misses of the main chain height cache.
+	fn height_cache_stats(&self) -> CacheStats;
+
+	/// Last branch shared by the lineages of the two tips, where their forks
+	/// diverge. Answered from a cache of the lineage pairs seen recently,
+	/// dropping the pairs of a tip that gets deleted.
+	fn common_ancestor(&self, a: &Tip, b: &Tip) -> u32;
+
+	/// Hits and misses of the common ancestor cache.
+	fn ancestor_cache_stats(&self) -> CacheStats;
+
+	/// Number of fork tips tracked, the head's included
+	fn tracked_fork_count(&self) -> Result<usize, Error>;
+
+	/// The tip whose last block is the provided hash, if any
+	fn tip_for_block(&self, h: &Hash) -> Result<Tip, Error>;
+
+	/// Tip of a new fork whose last block is the provided one, a block inside
+	/// one of the tracked forks rather than at its end. The lineage is the one
+	/// of the shortest fork the block is on, forked with a branch number no
+	/// tracked tip uses. Only the MAX_CHAIN_DIFF blocks under each tip are
+	/// looked at, not found if the block isn't in those. Nothing is saved,
+	/// the new fork gets tracked once a block extends it.
+	fn fork_tip(&self, h: &Hash) -> Result<Tip, Error>;
+
+	/// The head as it was when the main chain block at the provided height was
+	/// the head. Fork heights aren't recorded, so the lineage is the current
+	/// main chain one.
+	fn tip_as_of(&self, height: u64) -> Result<Tip, Error>;
+
+	/// The head buried under the provided number of confirmations, as it was
+	/// that many blocks ago on the current main chain. Not found if the chain
+	/// isn't that long.
+	fn confirmed_tip(&self, depth: u64) -> Result<Tip, Error>;
+
+	/// Whether our head has at least as much total difficulty as the best of
+	/// the tips advertised by our peers, meaning we're caught up. True without
+	/// any peer tip.
+	fn is_at_best_known_tip(&self, peer_tips: &[Tip]) -> Result<bool, Error>;
+
+	/// Records the total difficulty the main chain is expected to have at
+	/// the provided height, blocks reaching that height with another total
+	/// being refused. The blocks it finalizes only get reported to the chain
+	/// adapter when going through pipe::add_difficulty_checkpoint.
+	fn add_difficulty_checkpoint(&self, height: u64, total: Difficulty) -> Result<(), Error>;
+
+	/// The total difficulty expected at the provided height, if checkpointed.
+	fn get_difficulty_checkpoint(&self, height: u64) -> Result<Difficulty, Error>;
+
+	/// All the total difficulty checkpoints, by increasing height.
+	fn difficulty_checkpoints(&self) -> Result<Vec<DifficultyCheckpoint>, Error>;
+
+	/// Height below which main chain blocks are final and can't be reorged
+	/// out anymore. Either FINALITY_DEPTH blocks below the head or just above
+	/// the highest checkpoint on the main chain, whichever is higher.
+	fn finalized_height(&self) -> Result<u64, Error>;
+
+	/// Height below which the finalized blocks were already reported to the
+	/// chain adapter.
+	fn finalized_notified_height(&self) -> Result<u64, Error>;
+
+	/// Records the blocks below the provided height were reported finalized.
+	fn save_finalized_notified_height(&self, height: u64) -> Result<(), Error>;
+
+	/// Forces the head to the tracked fork ending with the provided block,
+	/// regardless of its total difficulty. Meant for manual intervention
+	/// only. The fork must have all its block bodies above the main chain and
+	/// can't start below the finalized height. The override is recorded in
+	/// the event log.
+	fn force_head(&self, h: &Hash) -> Result<Tip, Error>;
+
+	/// Proof that the provided block is on our main chain, to verify against
+	/// our current head, holding every header from the block to the head. Not
+	/// found if the block isn't on the main chain, ProofTooDeep if it's more
+	/// than MAX_PROOF_DEPTH blocks under the head.
+	fn main_chain_proof(&self, h: &Hash) -> Result<MainChainProof, Error>;
+
+	/// Locator of our main chain, at most MAX_LOCATOR_LEN blocks long.
+	fn block_locator(&self) -> Result<BlockLocator, Error>;
+
+	/// Where our main chain and the one described by the provided locator
+	/// diverge. Returns the height following the last block both chains have
+	/// in common, our block at that height and the lowest block of the other
+	/// chain above the common one that the locator includes (at that same
+	/// height when the locator is contiguous there). None if one chain is a
+	/// prefix of the other.
+	fn first_divergence(&self,
+	                    other_locator: &BlockLocator)
+	                    -> Result<Option<(u64, Hash, Hash)>, Error>;
+
+	/// Blocks a peer on the provided tip would need to get to our head. The
+	/// peer tip must be a block we know of.
+	fn chain_diff(&self, peer_tip: &Tip) -> Result<ChainDiff, Error>;
+
+	/// Checks the main chain is consistent from the provided height up to the
+	/// head: headers link to each other, heights increase one by one, the
+	/// height index matches and all block bodies above the body horizon are
+	/// present. Also checks all tips have the height of their last block.
+	/// Returns the first inconsistency found.
+	fn verify_chain(&self, from_height: u64) -> Result<(), Error>;
+
+	/// Regenerates the height index from the main chain, walking down from
+	/// the head, and removes the entries left above it. Height based lookups
+	/// still work without a sound index, walking the chain instead, but much
+	/// more slowly. Returns the number of entries written.
+	fn rebuild_height_index(&self) -> Result<u64, Error>;
+
+	/// Replays the main chain blocks from the provided height up to the head
+	/// through the adapter, calling block_replayed for each by increasing
+	/// height, so it can rebuild any state derived from the chain. The store
+	/// is left untouched. Fails with BlockPruned if the range starts below the
+	/// body horizon. Returns the number of blocks replayed.
+	fn replay(&self, from_height: u64, adapter: &ChainAdapter) -> Result<u64, Error>;
+
+	/// Progress made by the head since the provided baseline height and time
+	/// (in seconds), and whether it looks like we're stuck.
+	fn progress_since(&self,
+	                  baseline_height: u64,
+	                  baseline_time: u64,
+	                  now: u64)
+	                  -> Result<SyncProgress, Error>;
+}
+
+/// Iterator over block headers going backward from a starting block,
+/// following the links to previous blocks down to genesis.
+pub struct HeaderRevIter<'a> {
+	store: &'a ChainStore,
+	next: Option<Result<Hash, Error>>,
+	// scratch buffer reused for each header read
+	buf: Vec<u8>,
+}
+
+impl<'a> HeaderRevIter<'a> {
+	/// New iterator starting with the provided block, or failing right away
+	/// with the provided error.
+	pub fn new(store: &'a ChainStore, start: Result<Hash, Error>) -> HeaderRevIter<'a> {
+		HeaderRevIter {
+			store: store,
+			next: Some(start),
+			buf: vec![],
+		}
+	}
+}
+
+impl<'a> Iterator for HeaderRevIter<'a> {
+	type Item = Result<BlockHeader, Error>;
+
+	fn next(&mut self) -> Option<Result<BlockHeader, Error>> {
+		let res = match self.next.take() {
+			None => return None,
+			Some(Err(e)) => Err(e),
+			Some(Ok(h)) => self.store.get_block_header_buf(&h, &mut self.buf),
+		};
+		if let Ok(ref bh) = res {
+			if bh.height > 0 {
+				self.next = Some(Ok(bh.previous));
+			}
+		}
+		Some(res)
+	}
 }
 
 /// Bridge between the chain pipeline and the rest of the system. Handles
@@ -160,9 +1119,270 @@ pub trait ChainAdapter {
 	/// The blockchain pipeline has accepted this block as valid and added
 	/// it to our chain.
 	fn block_accepted(&self, b: &Block);
+
+	/// The main chain switched to another fork, the provided transactions
+	/// need to be reconciled with our pool.
+	fn reorged(&self, _txs: &ReorgTxSet) {}
+
+	/// The main chain block went below the finalized height and can't be
+	/// reorged out anymore. Called once per block, by increasing height.
+	fn block_finalized(&self, _header: &BlockHeader) {}
+
+	/// The main chain block is being replayed, blocks coming by strictly
+	/// increasing height. Only called by ChainStore::replay, blocks joining
+	/// the main chain as they're processed aren't reported here.
+	fn block_replayed(&self, _b: &Block) {}
 }
 
 pub struct NoopAdapter { }
 impl ChainAdapter for NoopAdapter {
 	fn block_accepted(&self, b: &Block) {}
 }
+
+/// Local admission rules layered on top of consensus, consulted by the
+/// pipeline for blocks that are otherwise valid, before they get saved.
+pub trait BlockPolicy {
+	/// Whether the block, extending the provided parent, can be accepted.
+	/// The reason for a refusal is reported back with the pipeline error.
+	fn admit(&self, b: &Block, parent: &BlockHeader) -> Result<(), String>;
+}
+
+/// Policy admitting every valid block.
+pub struct NoPolicy {}
+impl BlockPolicy for NoPolicy {
+	fn admit(&self, b: &Block, parent: &BlockHeader) -> Result<(), String> {
+		Ok(())
+	}
+}
+
+/// Sanity bounds on the difficulty a block can declare, checked before its
+/// proof of work. Besides the absolute bounds, the difficulty can't be more
+/// than max_drop times lower than its parent's. Consensus only lets the
+/// target go up by a few 1024th at each block, the cuckoo size increase
+/// halving the difficulty on top of it, so the default leaves some margin
+/// over that. A block can be as much harder than its parent as it likes.
+#[derive(Debug, Clone, Copy)]
+pub struct DifficultyBounds {
+	min: Difficulty,
+	max: Difficulty,
+	max_drop: u64,
+}
+
+impl DifficultyBounds {
+	/// Bounds accepting difficulties between min and max, and down to
+	/// max_drop times lower than the parent's. Fails if min is above max or
+	/// max_drop is zero.
+	pub fn new(min: Difficulty, max: Difficulty, max_drop: u64) -> Result<DifficultyBounds, String> {
+		if min > max {
+			return Err(format!("minimum difficulty {} above the maximum {}", min, max));
+		}
+		if max_drop == 0 {
+			return Err("difficulty drop ratio can't be zero".to_string());
+		}
+		Ok(DifficultyBounds {
+			min: min,
+			max: max,
+			max_drop: max_drop,
+		})
+	}
+
+	/// Range a block extending the provided parent must declare its
+	/// difficulty in.
+	pub fn range(&self, parent: &BlockHeader) -> (Difficulty, Difficulty) {
+		let parent_diff = Difficulty::from_target(&parent.target).0;
+		let min = cmp::min(cmp::max(self.min.0, parent_diff / self.max_drop), self.max.0);
+		(Difficulty(min), self.max)
+	}
+}
+
+impl Default for DifficultyBounds {
+	fn default() -> DifficultyBounds {
+		DifficultyBounds {
+			min: Difficulty::from_target(&genesis::genesis().header.target),
+			max: Difficulty(::std::u64::MAX),
+			max_drop: 4,
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use core::core::hash::ZERO_HASH;
+	use core::ser;
+	use secp::pedersen::Commitment;
+
+	#[test]
+	fn reject_corrupt_tip() {
+		let tip = Tip {
+			height: 1,
+			last_block_h: ZERO_HASH,
+			prev_block_h: ZERO_HASH,
+			total_difficulty: Difficulty(2),
+			lineage: Lineage::new().fork(1),
+		};
+		tip.validate().unwrap();
+
+		let corrupt = Tip { lineage: tip.lineage.fork(2), ..tip.clone() };
+		assert!(corrupt.validate().is_err());
+
+		let mut vec = Vec::new();
+		ser::serialize(&mut vec, &corrupt).unwrap();
+		match ser::deserialize::<Tip>(&mut &vec[..]) {
+			Err(ser::Error::CorruptedData) => {}
+			_ => panic!("corrupt tip should fail to deserialize"),
+		}
+	}
+
+	#[test]
+	fn lineage_common_ancestor() {
+		let main = Lineage::new();
+		let fork1 = main.fork(1);
+		let fork2 = fork1.fork(2);
+		let fork3 = main.fork(3);
+		assert_eq!(fork2.common_ancestor(&fork3), 0);
+		assert_eq!(fork3.common_ancestor(&fork2), 0);
+		assert_eq!(fork1.common_ancestor(&fork2), 1);
+		assert_eq!(main.common_ancestor(&fork1), 0);
+		assert_eq!(fork2.common_ancestor(&fork2), 2);
+	}
+
+	#[test]
+	fn tip_optional_fields() {
+		let tip = Tip {
+			height: 2,
+			last_block_h: ZERO_HASH,
+			prev_block_h: ZERO_HASH,
+			total_difficulty: Difficulty(10),
+			lineage: Lineage::new(),
+		};
+		let mut vec = Vec::new();
+		ser::serialize(&mut vec, &tip).unwrap();
+		let read = ser::deserialize::<Tip>(&mut &vec[..]).unwrap();
+		assert_eq!(read.total_difficulty, Difficulty(10));
+
+		// a newer writer's unknown field, at the end
+		let mut newer = vec.clone();
+		newer[9] |= 0b10;
+		newer.extend_from_slice(&[1, 2, 3]);
+		let read = ser::deserialize::<Tip>(&mut &newer[..]).unwrap();
+		assert_eq!(read.total_difficulty, Difficulty(10));
+		assert_eq!(read.height, 2);
+
+		// no total difficulty
+		let mut older = vec.clone();
+		older[9] = 0;
+		let len = older.len();
+		older.truncate(len - 8);
+		let read = ser::deserialize::<Tip>(&mut &older[..]).unwrap();
+		assert_eq!(read.total_difficulty, Difficulty(0));
+	}
+
+	fn proof(n: u8, fee: u64) -> TxProof {
+		TxProof {
+			remainder: Commitment([n; 33]),
+			sig: vec![n],
+			fee: fee,
+		}
+	}
+
+	fn block(proofs: Vec<TxProof>) -> Block {
+		Block {
+			header: BlockHeader::default(),
+			inputs: vec![],
+			outputs: vec![],
+			proofs: proofs,
+		}
+	}
+
+	#[test]
+	fn cap_locator_len() {
+		let locator = BlockLocator {
+			hashes: vec![ZERO_HASH; MAX_LOCATOR_LEN],
+			heights: vec![0; MAX_LOCATOR_LEN],
+		};
+		let data = ser::ser_vec(&locator).unwrap();
+		let read: BlockLocator = ser::deserialize(&mut &data[..]).unwrap();
+		assert_eq!(read, locator);
+
+		// a peer sending more gets refused
+		let mut data = data;
+		data[0] = MAX_LOCATOR_LEN as u8 + 1;
+		match ser::deserialize::<BlockLocator>(&mut &data[..]) {
+			Err(ser::Error::TooLargeReadErr) => {}
+			_ => panic!("should refuse a locator too long"),
+		}
+	}
+
+	#[test]
+	fn cap_proof_depth() {
+		let proof = MainChainProof { headers: vec![] };
+		let data = ser::ser_vec(&proof).unwrap();
+		assert!(ser::deserialize::<MainChainProof>(&mut &data[..]).is_ok());
+		assert!(!proof.verify(&ZERO_HASH));
+
+		// more headers than the depth allows get refused before reading them
+		let len = MAX_PROOF_DEPTH + 2;
+		let data: Vec<u8> = (0..8).map(|i| (len >> (56 - 8 * i)) as u8).collect();
+		match ser::deserialize::<MainChainProof>(&mut &data[..]) {
+			Err(ser::Error::TooLargeReadErr) => {}
+			_ => panic!("should refuse a proof too deep"),
+		}
+	}
+
+	#[test]
+	fn append_contiguous_height() {
+		let gen = ::core::genesis::genesis();
+		let mut b = ::core::genesis::genesis();
+		b.header.height = 1;
+		b.header.previous = gen.hash();
+		let tip = Tip::genesis(&gen).append(&b.header).unwrap();
+		assert_eq!(tip.height, 1);
+
+		// a block claiming to be higher than it is
+		b.header.height = 50;
+		match tip.append(&b.header) {
+			Err(Error::NonContiguousHeight { expected: 2, got: 50 }) => {}
+			_ => panic!("should refuse the lying height"),
+		}
+	}
+
+	#[test]
+	fn total_difficulty_overflow() {
+		let gen = ::core::genesis::genesis();
+		let mut b = ::core::genesis::genesis();
+		b.header.height = 1;
+		b.header.previous = gen.hash();
+		b.header.target = ::core::consensus::MAX_TARGET >> 1;
+		let tip = Tip::genesis(&gen);
+
+		// right up to the maximum is fine
+		let near_max = Tip { total_difficulty: Difficulty(::std::u64::MAX - 2), ..tip.clone() };
+		let full = near_max.append(&b.header).unwrap();
+		assert_eq!(full.total_difficulty, Difficulty(::std::u64::MAX));
+
+		// one more and the guard fires instead of wrapping
+		let over = Tip { total_difficulty: Difficulty(::std::u64::MAX - 1), ..tip };
+		match over.append(&b.header) {
+			Err(Error::DifficultyOverflow(1)) => {}
+			_ => panic!("should refuse to wrap the total difficulty"),
+		}
+		match HeaderTip::from_tip(&over).append(&b.header) {
+			Err(Error::DifficultyOverflow(1)) => {}
+			_ => panic!("should refuse to wrap the header total difficulty"),
+		}
+	}
+
+	#[test]
+	fn reorg_tx_set() {
+		let disconnected = vec![block(vec![proof(1, 1), proof(2, 1), proof(9, 0)]),
+		                        block(vec![proof(3, 1), proof(1, 1)])];
+		let connected = vec![block(vec![proof(2, 1), proof(4, 1), proof(8, 0)])];
+		let txs = ReorgTxSet::from_blocks(&disconnected, &connected);
+
+		let readd: Vec<u8> = txs.to_readd.iter().map(|p| p.sig[0]).collect();
+		let remove: Vec<u8> = txs.to_remove.iter().map(|p| p.sig[0]).collect();
+		assert_eq!(readd, vec![1, 3]);
+		assert_eq!(remove, vec![4]);
+	}
+}
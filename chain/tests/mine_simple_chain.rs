@@ -16,26 +16,62 @@ extern crate grin_core;
 extern crate grin_chain;
 extern crate rand;
 extern crate secp256k1zkp as secp;
+extern crate time;
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use rand::os::OsRng;
 
+use grin_chain::pipe::{PipelineOpts, StandardVerifier, Verifier};
+use grin_chain::store::TempDir;
 use grin_chain::types::*;
 use grin_core::pow;
 use grin_core::core;
 use grin_core::consensus;
+use grin_core::core::hash::Hashed;
+use grin_core::core::target::Difficulty;
+
+// Records the reorgs, the finalized and the replayed blocks reported to it
+struct RecordingAdapter {
+	reorgs: Mutex<usize>,
+	finalized: Mutex<Vec<u64>>,
+	replayed: Mutex<Vec<u64>>,
+}
+
+impl RecordingAdapter {
+	fn new() -> RecordingAdapter {
+		RecordingAdapter {
+			reorgs: Mutex::new(0),
+			finalized: Mutex::new(vec![]),
+			replayed: Mutex::new(vec![]),
+		}
+	}
+}
+
+impl ChainAdapter for RecordingAdapter {
+	fn block_accepted(&self, _: &core::Block) {}
+	fn reorged(&self, _: &ReorgTxSet) {
+		*self.reorgs.lock().unwrap() += 1;
+	}
+	fn block_finalized(&self, header: &core::BlockHeader) {
+		self.finalized.lock().unwrap().push(header.height);
+	}
+	fn block_replayed(&self, b: &core::Block) {
+		self.replayed.lock().unwrap().push(b.header.height);
+	}
+}
 
 #[test]
 fn mine_empty_chain() {
 	let mut rng = OsRng::new().unwrap();
-	let store = grin_chain::store::ChainKVStore::new(".grin".to_string()).unwrap();
+	let dir = TempDir::new("grin");
+	let store = grin_chain::store::ChainKVStore::new(dir.path()).unwrap();
 
   // save a genesis block
   let gen = grin_core::genesis::genesis(); 
   store.save_block(&gen).unwrap();
 
   // setup a new head tip
-  let tip = Tip::new(gen.hash());
+  let tip = Tip::genesis(&gen);
   store.save_head(&tip).unwrap();
 
   // mine and add a few blocks
@@ -57,13 +93,714 @@ fn mine_empty_chain() {
     b.header.pow = proof;
     b.header.nonce = nonce;
     b.header.target = diff_target;
-    grin_chain::pipe::process_block(&b, arc_store.clone(), adapter.clone(), grin_chain::pipe::EASY_POW).unwrap();
+    grin_chain::pipe::process_block(&b, arc_store.clone(), adapter.clone(), &easy_pow()).unwrap();
 
     // checking our new head
     let head = arc_store.clone().head().unwrap();
     assert_eq!(head.height, n);
     assert_eq!(head.last_block_h, b.hash());
+    assert_eq!(arc_store.head_height().unwrap(), n);
+    assert_eq!(arc_store.best_header_tip().unwrap().last_block_h, b.hash());
 
     prev = b;
   }
 }
+
+// Default pipeline with the easier proof of work the test blocks are mined
+// with
+fn easy_pow() -> PipelineOpts {
+	PipelineOpts::new(grin_chain::pipe::EASY_POW)
+}
+
+// Builds a block on top of the provided one and solves its (easy) proof of
+// work, a minute after its parent.
+fn mine_block(prev: &core::Block, reward_key: secp::key::SecretKey) -> core::Block {
+	mine_harder_block(prev, reward_key, 0)
+}
+
+// Mines a block with a target lower than required by the provided shift
+fn mine_harder_block(prev: &core::Block, reward_key: secp::key::SecretKey, shift: usize) -> core::Block {
+	let mut b = core::Block::new(&prev.header, vec![], reward_key).unwrap();
+	b.header.timestamp = prev.header.timestamp + time::Duration::seconds(60);
+
+	let (diff_target, _) = consensus::next_target(b.header.timestamp.to_timespec().sec,
+	                                              prev.header.timestamp.to_timespec().sec,
+	                                              prev.header.target,
+	                                              prev.header.cuckoo_len);
+	let diff_target = diff_target >> shift;
+	b.header.target = diff_target;
+	let (proof, nonce) = pow::pow_size(&b, diff_target, 15).unwrap();
+	b.header.pow = proof;
+	b.header.nonce = nonce;
+	b
+}
+
+// What the tests mine and process blocks with: a store initialized with the
+// genesis block in its own temp dir, removed once the test is done, the
+// pipeline options, an adapter and a key for the block rewards
+struct Setup {
+	_dir: TempDir,
+	gen: core::Block,
+	store: Arc<grin_chain::store::ChainKVStore>,
+	opts: PipelineOpts,
+	adapter: Arc<NoopAdapter>,
+	reward_key: secp::key::SecretKey,
+}
+
+fn setup(name: &str) -> Setup {
+	let dir = TempDir::new(name);
+	let store = grin_chain::store::ChainKVStore::new(dir.path()).unwrap();
+	let gen = grin_core::genesis::genesis();
+	store.init(&gen).unwrap();
+	Setup {
+		_dir: dir,
+		gen: gen,
+		store: Arc::new(store),
+		opts: easy_pow(),
+		adapter: Arc::new(NoopAdapter {}),
+		reward_key: new_key(),
+	}
+}
+
+// New random key for block rewards, a different one for each miner
+fn new_key() -> secp::key::SecretKey {
+	let mut rng = OsRng::new().unwrap();
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	secp::key::SecretKey::new(&secp, &mut rng)
+}
+
+#[test]
+fn extend_side_chain() {
+	let Setup { _dir, gen, store: arc_store, opts, reward_key, .. } = setup("grin_side");
+	let heads = arc_store.subscribe_head();
+	let adapter = Arc::new(RecordingAdapter::new());
+
+	// main chain of 3 blocks
+	let b1 = mine_block(&gen, reward_key);
+	grin_chain::pipe::process_block(&b1, arc_store.clone(), adapter.clone(), &opts).unwrap();
+	let b2 = mine_block(&b1, reward_key);
+	grin_chain::pipe::process_block(&b2, arc_store.clone(), adapter.clone(), &opts).unwrap();
+	let b3 = mine_block(&b2, reward_key);
+	grin_chain::pipe::process_block(&b3, arc_store.clone(), adapter.clone(), &opts).unwrap();
+	let head = arc_store.head().unwrap();
+	assert_eq!(head.height, 3);
+	let notified: Vec<u64> = heads.try_iter().map(|t| t.height).collect();
+	assert_eq!(notified, vec![1, 2, 3]);
+
+	// blocks are 60 secs apart
+	let gen_ts = gen.header.timestamp.to_timespec().sec as u64;
+	assert!(arc_store.find_height_by_timestamp(gen_ts - 1).is_err());
+	assert_eq!(arc_store.find_height_by_timestamp(gen_ts).unwrap(), 0);
+	assert_eq!(arc_store.find_height_by_timestamp(gen_ts + 119).unwrap(), 1);
+	assert_eq!(arc_store.find_height_by_timestamp(gen_ts + 120).unwrap(), 2);
+	assert_eq!(arc_store.find_height_by_timestamp(gen_ts + 1000).unwrap(), 3);
+
+	// a side chain from another miner forking after the first block, tracked as
+	// its own tip
+	let fork_key = new_key();
+	let fork_b2 = mine_block(&b1, fork_key);
+	arc_store.save_block(&fork_b2).unwrap();
+	let side_tip = Tip {
+		lineage: head.lineage.fork(1),
+		..Tip::genesis(&gen).append(&b1.header).unwrap().append(&fork_b2.header).unwrap()
+	};
+	arc_store.save_tip(&side_tip).unwrap();
+
+	// extending the side chain to the same height as the head doesn't move the head
+	let fork_b3 = mine_block(&fork_b2, fork_key);
+	let res = grin_chain::pipe::process_block(&fork_b3, arc_store.clone(), adapter.clone(), &opts).unwrap();
+	assert!(res.is_none());
+	assert_eq!(arc_store.head().unwrap().last_block_h, head.last_block_h);
+	assert_eq!(arc_store.best_header_tip().unwrap().last_block_h, head.last_block_h);
+	assert!(heads.try_recv().is_err());
+
+	let new_side_tip = arc_store.tip_for_block(&fork_b3.hash()).unwrap();
+	assert_eq!(new_side_tip.height, 3);
+	assert_eq!(new_side_tip.prev_block_h, fork_b2.hash());
+	assert_eq!(new_side_tip.lineage.last_branch(), 1);
+	assert!(arc_store.tip_for_block(&fork_b2.hash()).is_err());
+	assert_eq!(arc_store.get_tips().unwrap().len(), 2);
+	arc_store.verify_chain(0).unwrap();
+	assert_eq!(arc_store.get_header_by_height(2).unwrap().hash(), b2.hash());
+
+	// a child of the side tip retargets from the side tip last block
+	let ts = fork_b3.header.timestamp.to_timespec().sec + 60;
+	let (target, cuckoo_len) = arc_store.next_target(&new_side_tip, ts).unwrap();
+	assert_eq!((target, cuckoo_len),
+	           consensus::next_target(ts, ts - 60, fork_b3.header.target, fork_b3.header.cuckoo_len));
+
+	// one more block and the side chain has more work, becoming the main chain
+	assert_eq!(*adapter.reorgs.lock().unwrap(), 0);
+	let fork_b4 = mine_block(&fork_b3, fork_key);
+	let res = grin_chain::pipe::process_block(&fork_b4, arc_store.clone(), adapter.clone(), &opts).unwrap();
+	assert_eq!(res.unwrap().last_block_h, fork_b4.hash());
+	assert_eq!(*adapter.reorgs.lock().unwrap(), 1);
+	assert_eq!(arc_store.get_stale_blocks(0).unwrap(), vec![b2.hash(), b3.hash()]);
+	assert_eq!(arc_store.get_stale_blocks(3).unwrap(), vec![b3.hash()]);
+
+	// b1 is still on the main chain, b2 not anymore
+	let proof = arc_store.main_chain_proof(&b1.hash()).unwrap();
+	assert_eq!(proof.block_hash(), Some(b1.hash()));
+	assert_eq!(proof.headers.len(), 4);
+	assert!(proof.verify(&fork_b4.hash()));
+	assert!(!proof.verify(&b3.hash()));
+	assert!(arc_store.main_chain_proof(&b2.hash()).is_err());
+	assert_eq!(arc_store.get_header_by_height(2).unwrap().hash(), fork_b2.hash());
+	assert_eq!(arc_store.get_tips().unwrap().len(), 2);
+	let main_chain: Vec<_> = arc_store.iter_main_chain_rev().map(|bh| bh.unwrap().hash()).collect();
+	assert_eq!(main_chain,
+	           vec![fork_b4.hash(), fork_b3.hash(), fork_b2.hash(), b1.hash(), gen.hash()]);
+	let locator = arc_store.block_locator().unwrap();
+	assert_eq!(locator.hashes,
+	           vec![fork_b4.hash(), fork_b3.hash(), fork_b2.hash(), gen.hash()]);
+	assert_eq!(locator.heights, vec![4, 3, 2, 0]);
+
+	// a node still on the old main chain diverges right after b1
+	let old_locator = BlockLocator {
+		hashes: vec![b3.hash(), b2.hash(), b1.hash(), gen.hash()],
+		heights: vec![3, 2, 1, 0],
+	};
+	assert_eq!(arc_store.first_divergence(&old_locator).unwrap(),
+	           Some((2, fork_b2.hash(), b2.hash())));
+	let behind_locator = BlockLocator {
+		hashes: vec![fork_b3.hash(), fork_b2.hash(), b1.hash(), gen.hash()],
+		heights: vec![3, 2, 1, 0],
+	};
+	assert_eq!(arc_store.first_divergence(&behind_locator).unwrap(), None);
+	arc_store.verify_chain(0).unwrap();
+
+	// compared on total difficulty, a lower peer tip with more work is ahead
+	let new_head = arc_store.head().unwrap();
+	assert_eq!(arc_store.total_work().unwrap(), new_head.total_difficulty);
+	assert!(arc_store.is_at_best_known_tip(&[]).unwrap());
+	assert!(arc_store.is_at_best_known_tip(&[head.clone(), new_head.clone()]).unwrap());
+	let ahead = Tip { height: 3, total_difficulty: Difficulty(new_head.total_difficulty.0 + 1), ..head };
+	assert!(!arc_store.is_at_best_known_tip(&[ahead]).unwrap());
+
+	// an operator can force the head back on the lighter fork, but only to a tip
+	match grin_chain::pipe::force_head(&b2.hash(), arc_store.clone(), adapter.clone()) {
+		Err(grin_chain::pipe::Error::StoreErr(Error::NotFoundErr)) => {}
+		_ => panic!("should only force the head to a tip"),
+	}
+	let forced = grin_chain::pipe::force_head(&b3.hash(), arc_store.clone(), adapter.clone()).unwrap();
+	assert_eq!(forced.last_block_h, b3.hash());
+	assert_eq!(arc_store.head().unwrap().last_block_h, b3.hash());
+	assert_eq!(arc_store.get_header_by_height(2).unwrap().hash(), b2.hash());
+	assert_eq!(*adapter.reorgs.lock().unwrap(), 2);
+	assert_eq!(arc_store.get_stale_blocks(0).unwrap(),
+	           vec![fork_b2.hash(), fork_b3.hash(), fork_b4.hash()]);
+	let last_event = arc_store.events_since(0).unwrap().pop().unwrap();
+	assert_eq!((last_event.kind, last_event.hash), (ChainEventKind::Forced, b3.hash()));
+	arc_store.verify_chain(0).unwrap();
+}
+
+#[test]
+fn migrate_store() {
+	let Setup { _dir, gen, store: src, opts, adapter, reward_key } = setup("grin_migrate_src");
+	let b1 = mine_block(&gen, reward_key);
+	grin_chain::pipe::process_block(&b1, src.clone(), adapter.clone(), &opts).unwrap();
+	let b2 = mine_block(&b1, reward_key);
+	grin_chain::pipe::process_block(&b2, src.clone(), adapter.clone(), &opts).unwrap();
+
+	let dst_dir = TempDir::new("grin_migrate_dst");
+	let dst = grin_chain::store::ChainKVStore::new(dst_dir.path()).unwrap();
+	let count = grin_chain::store::migrate(&*src, &dst).unwrap();
+	assert_eq!(count, 3);
+	assert_eq!(dst.head().unwrap().last_block_h, b2.hash());
+	assert_eq!(dst.get_block(&b1.hash()).unwrap().hash(), b1.hash());
+	assert_eq!(dst.get_tips().unwrap().len(), 1);
+	dst.verify_chain(0).unwrap();
+
+	// metadata restored onto the bare blocks gives back the same chain
+	src.add_difficulty_checkpoint(2, src.head().unwrap().total_difficulty).unwrap();
+	let mut backup = vec![];
+	src.export_metadata(&mut backup).unwrap();
+	let restored_dir = TempDir::new("grin_metadata");
+	let restored = grin_chain::store::ChainKVStore::new(restored_dir.path()).unwrap();
+	for b in &[&gen, &b1, &b2] {
+		restored.save_block(b).unwrap();
+	}
+	restored.import_metadata(&backup[..]).unwrap();
+	assert_eq!(restored.head().unwrap().last_block_h, b2.hash());
+	assert_eq!(restored.get_header_by_height(1).unwrap().hash(), b1.hash());
+	assert_eq!(restored.get_tips().unwrap().len(), 1);
+	assert_eq!(restored.best_header_tip().unwrap().last_block_h, b2.hash());
+	assert_eq!(restored.difficulty_checkpoints().unwrap(), src.difficulty_checkpoints().unwrap());
+	restored.verify_chain(0).unwrap();
+
+	// unknown versions are refused
+	backup[0] = CHAIN_METADATA_VERSION + 1;
+	assert!(restored.import_metadata(&backup[..]).is_err());
+}
+
+#[test]
+fn difficulty_checkpoint() {
+	let Setup { _dir, gen, store: arc_store, opts, reward_key, .. } = setup("grin_diff_checkpoint");
+	let adapter = Arc::new(RecordingAdapter::new());
+	let b1 = mine_block(&gen, reward_key);
+	let total = add_difficulty(arc_store.head().unwrap().total_difficulty, &b1.header).unwrap();
+
+	// a diverging total difficulty is refused
+	arc_store.add_difficulty_checkpoint(1, Difficulty(total.0 + 1)).unwrap();
+	match grin_chain::pipe::process_block(&b1, arc_store.clone(), adapter.clone(), &opts) {
+		Err(grin_chain::pipe::Error::DifficultyCheckpointMismatch(1, _, got)) => assert_eq!(got, total),
+		_ => panic!("should refuse a block not matching the checkpoint"),
+	}
+	assert_eq!(arc_store.head().unwrap().height, 0);
+
+	arc_store.add_difficulty_checkpoint(1, total).unwrap();
+	grin_chain::pipe::process_block(&b1, arc_store.clone(), adapter.clone(), &opts).unwrap();
+	assert_eq!(arc_store.head().unwrap().total_difficulty, total);
+
+	// the checkpointed block and all below it are final, reported only once
+	assert_eq!(arc_store.finalized_height().unwrap(), 2);
+	assert_eq!(*adapter.finalized.lock().unwrap(), vec![0, 1]);
+	let b2 = mine_block(&b1, reward_key);
+	grin_chain::pipe::process_block(&b2, arc_store.clone(), adapter.clone(), &opts).unwrap();
+	assert_eq!(*adapter.finalized.lock().unwrap(), vec![0, 1]);
+
+	// a new checkpoint finalizes blocks without waiting for the head to move
+	let total = arc_store.head().unwrap().total_difficulty;
+	grin_chain::add_difficulty_checkpoint(2, total, arc_store.clone(), adapter.clone()).unwrap();
+	assert_eq!(*adapter.finalized.lock().unwrap(), vec![0, 1, 2]);
+
+	// replaying the main chain only goes through the provided adapter
+	let replayed = RecordingAdapter::new();
+	assert_eq!(arc_store.replay(0, &replayed).unwrap(), 3);
+	assert_eq!(*replayed.replayed.lock().unwrap(), vec![0, 1, 2]);
+	assert!(adapter.replayed.lock().unwrap().is_empty());
+	arc_store.prune_bodies(1).unwrap();
+	match arc_store.replay(0, &replayed) {
+		Err(Error::BlockPruned(0)) => {}
+		_ => panic!("should refuse to replay pruned blocks"),
+	}
+	assert_eq!(arc_store.replay(1, &replayed).unwrap(), 2);
+	assert_eq!(*replayed.replayed.lock().unwrap(), vec![0, 1, 2, 1, 2]);
+}
+
+// Refuses any block built on the genesis block
+struct NoGenesisChild {
+	gen: grin_core::core::hash::Hash,
+}
+
+impl BlockPolicy for NoGenesisChild {
+	fn admit(&self, _: &core::Block, parent: &core::BlockHeader) -> Result<(), String> {
+		if parent.hash() == self.gen {
+			return Err("child of genesis".to_string());
+		}
+		Ok(())
+	}
+}
+
+#[test]
+fn refuse_by_policy() {
+	let Setup { _dir, gen, store: arc_store, opts, adapter, reward_key } = setup("grin_policy");
+	let policy = Arc::new(NoGenesisChild { gen: gen.hash() });
+	let b1 = mine_block(&gen, reward_key);
+	let pipeline = opts.clone().with_policy(policy);
+	match grin_chain::pipe::process_block(&b1, arc_store.clone(), adapter.clone(), &pipeline) {
+		Err(grin_chain::pipe::Error::PolicyRejected(reason)) => assert_eq!(reason, "child of genesis"),
+		_ => panic!("the policy should refuse the block"),
+	}
+	assert!(arc_store.get_block(&b1.hash()).is_err());
+
+	// admitted without the policy
+	grin_chain::pipe::process_block(&b1, arc_store.clone(), adapter.clone(), &opts).unwrap();
+	assert_eq!(arc_store.head().unwrap().last_block_h, b1.hash());
+}
+
+#[test]
+fn repair_missing_head_body() {
+	let Setup { _dir, gen, store: arc_store, opts, adapter, reward_key } = setup("grin_repair");
+	let b1 = mine_block(&gen, reward_key);
+	grin_chain::pipe::process_block(&b1, arc_store.clone(), adapter.clone(), &opts).unwrap();
+	let b2 = mine_block(&b1, reward_key);
+	grin_chain::pipe::process_block(&b2, arc_store.clone(), adapter.clone(), &opts).unwrap();
+	let b2_diff = arc_store.head().unwrap().total_difficulty;
+
+	// lose the head body but keep its header
+	arc_store.delete_block(&b2.hash()).unwrap();
+	arc_store.save_block_header(&b2.header).unwrap();
+	match arc_store.head_block() {
+		Err(grin_chain::types::Error::HeadBodyMissing(h)) => assert_eq!(h, b2.hash()),
+		_ => panic!("head body should be missing"),
+	}
+
+	let head = arc_store.repair_head().unwrap();
+	assert_eq!(head.height, 1);
+	assert_eq!(head.last_block_h, b1.hash());
+	assert_eq!(head.prev_block_h, gen.hash());
+	assert!(head.total_difficulty < b2_diff);
+	assert_eq!(arc_store.head_block().unwrap().hash(), b1.hash());
+	arc_store.verify_chain(0).unwrap();
+
+	// genesis, b1 and b2 connected then b2 disconnected by the repair
+	let events = arc_store.events_since(0).unwrap();
+	let heights: Vec<(u64, u64)> = events.iter().map(|e| (e.seq, e.height)).collect();
+	assert_eq!(heights, vec![(1, 0), (2, 1), (3, 2), (4, 2)]);
+	assert_eq!(events[3].kind, grin_chain::types::ChainEventKind::Disconnected);
+	assert_eq!(events[3].hash, b2.hash());
+	assert_eq!(arc_store.events_since(3).unwrap(), vec![events[3].clone()]);
+
+	// the head we had before mining b1
+	let gen_tip = arc_store.tip_as_of(0).unwrap();
+	assert_eq!(gen_tip.last_block_h, gen.hash());
+	assert_eq!(gen_tip.total_difficulty, Tip::genesis(&gen).total_difficulty);
+	assert_eq!(arc_store.tip_as_of(1).unwrap().total_difficulty, head.total_difficulty);
+	assert!(arc_store.tip_as_of(2).is_err());
+	assert_eq!(arc_store.confirmed_tip(1).unwrap().last_block_h, gen.hash());
+	assert_eq!(arc_store.confirmed_tip(0).unwrap().last_block_h, head.last_block_h);
+	assert!(arc_store.confirmed_tip(2).is_err());
+
+	arc_store.truncate_events(1).unwrap();
+	assert_eq!(arc_store.events_since(0).unwrap()[0].seq, 2);
+
+	// a head saved before tips carried their total difficulty reads with
+	// none, walking it back must not wrap around
+	arc_store.save_head(&Tip { total_difficulty: Difficulty(0), ..head.clone() }).unwrap();
+	match arc_store.tip_as_of(0) {
+		Err(Error::InconsistentChain(1, _)) => {}
+		_ => panic!("should refuse a total difficulty going negative"),
+	}
+	arc_store.save_head(&head).unwrap();
+
+	// repairing down to genesis gives the genesis tip
+	arc_store.delete_block(&b1.hash()).unwrap();
+	arc_store.save_block_header(&b1.header).unwrap();
+	let head = arc_store.repair_head().unwrap();
+	let gen_tip = Tip::genesis(&gen);
+	assert_eq!(head.height, 0);
+	assert_eq!(head.last_block_h, gen_tip.last_block_h);
+	assert_eq!(head.prev_block_h, gen_tip.prev_block_h);
+	assert_eq!(head.total_difficulty, gen_tip.total_difficulty);
+}
+
+#[test]
+fn persist_orphans() {
+	let Setup { _dir, gen, store: arc_store, reward_key, .. } = setup("grin_orphans");
+	let b1 = mine_block(&gen, reward_key);
+	let b2 = mine_block(&b1, reward_key);
+	let b2_hash = b2.hash();
+
+	// an orphan that expired while we were down
+	let b3 = mine_block(&b2, reward_key);
+	let b3_hash = b3.hash();
+	let other_b3 = mine_block(&b2, reward_key);
+	arc_store.save_orphan(&grin_chain::types::Orphan { block: b3, received: 0 }).unwrap();
+
+	{
+		let pool = grin_chain::OrphanPool::persistent(arc_store.clone(), 10, 600).unwrap();
+		pool.add(b2).unwrap();
+		assert_eq!(pool.children_of(&b1.hash()), vec![b2_hash]);
+	}
+
+	// as after a restart
+	let pool = grin_chain::OrphanPool::persistent(arc_store.clone(), 10, 600).unwrap();
+	assert_eq!(pool.len(), 1);
+	assert!(pool.contains(&b2_hash));
+	assert!(!pool.contains(&b3_hash));
+
+	// another child of b2 only misses b1 to connect, b2 being in the pool
+	assert_eq!(pool.missing_ancestors(&*arc_store, &other_b3, 10).unwrap(), vec![b1.hash()]);
+	arc_store.save_block(&b1).unwrap();
+	assert!(pool.missing_ancestors(&*arc_store, &other_b3, 10).unwrap().is_empty());
+
+	assert_eq!(pool.remove(&b2_hash).unwrap().unwrap().hash(), b2_hash);
+	let pool = grin_chain::OrphanPool::persistent(arc_store.clone(), 10, 600).unwrap();
+	assert_eq!(pool.len(), 0);
+}
+
+#[test]
+fn rollback_failed_migration() {
+	let Setup { _dir, gen, store: src, opts, adapter, reward_key } = setup("grin_rollback_src");
+	let b1 = mine_block(&gen, reward_key);
+	grin_chain::pipe::process_block(&b1, src.clone(), adapter.clone(), &opts).unwrap();
+	let b2 = mine_block(&b1, reward_key);
+	grin_chain::pipe::process_block(&b2, src.clone(), adapter.clone(), &opts).unwrap();
+
+	// a side fork whose block is gone makes the copy fail after the main
+	// chain and its tip
+	let fork_key = new_key();
+	let side_b2 = mine_block(&b1, fork_key);
+	grin_chain::pipe::process_block(&side_b2, src.clone(), adapter.clone(), &opts).unwrap();
+	assert_eq!(src.get_tips().unwrap().len(), 2);
+	src.delete_block(&side_b2.hash()).unwrap();
+
+	let dst_dir = TempDir::new("grin_rollback_dst");
+	let dst = grin_chain::store::ChainKVStore::new(dst_dir.path()).unwrap();
+	let gen_tip = dst.init(&gen).unwrap();
+	let events = dst.events_since(0).unwrap().len();
+	assert!(grin_chain::store::migrate(&*src, &dst).is_err());
+
+	// back to the freshly initialized store
+	let head = dst.head().unwrap();
+	assert_eq!((head.last_block_h, head.height), (gen_tip.last_block_h, 0));
+	let tips = dst.get_tips().unwrap();
+	assert_eq!(tips.len(), 1);
+	assert_eq!(tips[0].last_block_h, gen.hash());
+	assert_eq!(dst.best_header_tip().unwrap().last_block_h, gen.hash());
+	assert!(dst.get_header_by_height(1).is_err());
+	assert_eq!(dst.events_since(0).unwrap().len(), events);
+	assert!(dst.get_block(&gen.hash()).is_ok());
+	assert!(dst.get_block_header(&b1.hash()).is_err());
+	assert!(dst.get_block_header(&b2.hash()).is_err());
+	dst.verify_chain(0).unwrap();
+}
+
+#[test]
+fn prune_and_migrate() {
+	let Setup { _dir, gen, store: src, opts, adapter, reward_key } = setup("grin_prune_src");
+	let b1 = mine_block(&gen, reward_key);
+	grin_chain::pipe::process_block(&b1, src.clone(), adapter.clone(), &opts).unwrap();
+	let b2 = mine_block(&b1, reward_key);
+	grin_chain::pipe::process_block(&b2, src.clone(), adapter.clone(), &opts).unwrap();
+
+	assert_eq!(src.body_horizon().unwrap(), 0);
+	src.prune_bodies(2).unwrap();
+	assert_eq!(src.body_horizon().unwrap(), 2);
+	match src.get_block(&b1.hash()) {
+		Err(grin_chain::types::Error::BlockPruned(1)) => {}
+		_ => panic!("b1 body should be pruned"),
+	}
+	assert_eq!(src.get_block_header(&b1.hash()).unwrap().hash(), b1.hash());
+	assert_eq!(src.get_block(&b2.hash()).unwrap().hash(), b2.hash());
+	match src.get_block(&grin_core::core::hash::ZERO_HASH) {
+		Err(grin_chain::types::Error::NotFoundErr) => {}
+		_ => panic!("unknown block should not be found"),
+	}
+	src.verify_chain(0).unwrap();
+
+	let dst_dir = TempDir::new("grin_prune_dst");
+	let dst = grin_chain::store::ChainKVStore::new(dst_dir.path()).unwrap();
+	assert_eq!(grin_chain::store::migrate(&*src, &dst).unwrap(), 3);
+	assert_eq!(dst.body_horizon().unwrap(), 2);
+	assert!(dst.get_block(&gen.hash()).is_err());
+	dst.verify_chain(0).unwrap();
+}
+
+#[test]
+fn refuse_difficulty_out_of_range() {
+	let Setup { _dir, gen, store: arc_store, opts, adapter, reward_key } = setup("grin_diff_bounds");
+
+	// a floor above the block difficulty
+	let b1 = mine_harder_block(&gen, reward_key, 3);
+	let bounds = DifficultyBounds::new(Difficulty(16), Difficulty(::std::u64::MAX), 4).unwrap();
+	let pipeline = opts.clone().with_bounds(bounds);
+	match grin_chain::pipe::process_block(&b1, arc_store.clone(), adapter.clone(), &pipeline) {
+		Err(grin_chain::pipe::Error::DifficultyOutOfRange { got, .. }) => assert_eq!(got, Difficulty(8)),
+		_ => panic!("should refuse a difficulty below the floor"),
+	}
+	// 8 times harder than its parent is fine by consensus
+	grin_chain::pipe::process_block(&b1, arc_store.clone(), adapter.clone(), &opts).unwrap();
+
+	// way easier than its parent, refused before even checking the pow
+	let mut lying = mine_block(&b1, reward_key);
+	lying.header.target = consensus::MAX_TARGET;
+	match grin_chain::pipe::process_block(&lying, arc_store.clone(), adapter.clone(), &opts) {
+		Err(grin_chain::pipe::Error::DifficultyOutOfRange { got, min, .. }) => {
+			assert_eq!(got, Difficulty(1));
+			assert_eq!(min, Difficulty(2));
+		}
+		_ => panic!("should refuse a difficulty drop"),
+	}
+	assert!(DifficultyBounds::new(Difficulty(1), Difficulty(2), 0).is_err());
+	assert!(DifficultyBounds::new(Difficulty(3), Difficulty(2), 4).is_err());
+	assert_eq!(arc_store.head().unwrap().height, 1);
+}
+
+#[test]
+fn checkpoint_fork_choice() {
+	let Setup { _dir, gen, store: arc_store, opts, adapter, reward_key } =
+		setup("grin_checkpoint_fork");
+
+	// main chain of 2 blocks and a fork through a twice harder block, as
+	// worked as the head
+	let b1 = mine_block(&gen, reward_key);
+	grin_chain::pipe::process_block(&b1, arc_store.clone(), adapter.clone(), &opts).unwrap();
+	let b2 = mine_block(&b1, reward_key);
+	grin_chain::pipe::process_block(&b2, arc_store.clone(), adapter.clone(), &opts).unwrap();
+	let head = arc_store.head().unwrap();
+	let fork_b1 = mine_harder_block(&gen, reward_key, 1);
+	arc_store.save_block(&fork_b1).unwrap();
+	let side_tip = Tip {
+		lineage: head.lineage.fork(1),
+		..Tip::genesis(&gen).append(&fork_b1.header).unwrap()
+	};
+	assert_eq!(side_tip.total_difficulty, head.total_difficulty);
+	arc_store.save_tip(&side_tip).unwrap();
+
+	// checkpointing our main chain after the fork got in
+	let checkpointed = arc_store.get_header_by_height(0).unwrap();
+	let total = add_difficulty(Difficulty::from_target(&checkpointed.target), &b1.header).unwrap();
+	arc_store.add_difficulty_checkpoint(1, total).unwrap();
+
+	// the fork now has more work but can't win
+	let fork_b2 = mine_block(&fork_b1, reward_key);
+	match grin_chain::pipe::process_block(&fork_b2, arc_store.clone(), adapter.clone(), &opts) {
+		Err(grin_chain::pipe::Error::CheckpointMismatch(1, expected, got)) => {
+			assert_eq!(expected, total);
+			assert!(got > total);
+		}
+		_ => panic!("should refuse a fork disagreeing with a checkpoint"),
+	}
+	assert_eq!(arc_store.head().unwrap().last_block_h, head.last_block_h);
+	arc_store.verify_chain(0).unwrap();
+}
+
+// Delegates to the standard verifier, counting the headers and bodies verified
+struct CountingVerifier {
+	inner: StandardVerifier,
+	verified: Mutex<(usize, usize)>,
+}
+
+impl Verifier for CountingVerifier {
+	fn verify_header(&self,
+	                 b: &core::Block,
+	                 parent: &core::BlockHeader)
+	                 -> Result<(), grin_chain::pipe::Error> {
+		self.verified.lock().unwrap().0 += 1;
+		self.inner.verify_header(b, parent)
+	}
+
+	fn verify_body(&self, b: &core::Block) -> Result<(), grin_chain::pipe::Error> {
+		self.verified.lock().unwrap().1 += 1;
+		self.inner.verify_body(b)
+	}
+}
+
+#[test]
+fn custom_verifier() {
+	let Setup { _dir, gen, store: arc_store, opts, adapter, reward_key } = setup("grin_verifier");
+	let verifier = Arc::new(CountingVerifier {
+		inner: StandardVerifier::new(grin_chain::pipe::EASY_POW),
+		verified: Mutex::new((0, 0)),
+	});
+	let pipeline = opts.with_verifier(verifier.clone());
+
+	// a broken pow is still caught through the custom verifier, before
+	// looking at signatures
+	let mut bad = mine_block(&gen, reward_key);
+	bad.header.nonce += 1;
+	match grin_chain::pipe::process_block(&bad, arc_store.clone(), adapter.clone(), &pipeline) {
+		Err(grin_chain::pipe::Error::InvalidPow) => {}
+		_ => panic!("should refuse the invalid pow"),
+	}
+	assert_eq!(*verifier.verified.lock().unwrap(), (1, 0));
+
+	let b1 = mine_block(&gen, reward_key);
+	grin_chain::pipe::process_block(&b1, arc_store.clone(), adapter.clone(), &pipeline).unwrap();
+	assert_eq!(arc_store.head().unwrap().height, 1);
+	assert_eq!(*verifier.verified.lock().unwrap(), (2, 1));
+}
+
+#[test]
+fn import_with_report() {
+	let Setup { _dir, gen, store: arc_store, opts, adapter, reward_key } = setup("grin_import");
+
+	let b1 = mine_block(&gen, reward_key);
+	let b2 = mine_block(&b1, reward_key);
+	let b3 = mine_block(&b2, reward_key);
+	let b4 = mine_block(&b3, reward_key);
+	let mut bad_b3 = mine_block(&b2, reward_key);
+	bad_b3.header.nonce += 1;
+	// only the header of side_b2 is known, its child extends no block we have
+	let side_b2 = mine_harder_block(&b1, reward_key, 1);
+	arc_store.save_block_header(&side_b2.header).unwrap();
+	let side_b3 = mine_block(&side_b2, reward_key);
+
+	// b3 is missing, making b4 an orphan, while side_b3 won't ever connect
+	let blocks = vec![b1, b2, bad_b3, b4, side_b3];
+	let report = grin_chain::import_blocks(&blocks, arc_store.clone(), adapter.clone(), &opts).unwrap();
+	assert_eq!(report.accepted, 2);
+	assert_eq!(report.duplicates, 0);
+	assert_eq!(report.orphaned, vec![blocks[3].hash()]);
+	assert_eq!(report.rejected.len(), 2);
+	match report.rejected[0] {
+		(h, grin_chain::pipe::Error::InvalidPow) => assert_eq!(h, blocks[2].hash()),
+		_ => panic!("should reject the invalid pow"),
+	}
+	match report.rejected[1] {
+		(h, grin_chain::pipe::Error::Unfit(_)) => assert_eq!(h, blocks[4].hash()),
+		_ => panic!("should reject the block extending no block we have"),
+	}
+	assert_eq!(arc_store.head().unwrap().height, 2);
+
+	// importing again only retries what was missing
+	let mut blocks = blocks;
+	let retried = vec![blocks.remove(1), b3];
+	let first_op = report.op_id;
+	let report = grin_chain::import_blocks(&retried, arc_store.clone(), adapter.clone(), &opts).unwrap();
+	// each import is its own operation
+	assert!(report.op_id > first_op);
+	assert_eq!(report.duplicates, 1);
+	assert_eq!(report.accepted, 1);
+	assert_eq!(arc_store.head().unwrap().height, 3);
+}
+
+#[test]
+fn pause_and_resume() {
+	let Setup { _dir, gen, store: arc_store, opts, adapter, reward_key } = setup("grin_pause");
+	let gate = Arc::new(grin_chain::PipelineGate::new());
+	let pipeline = opts.with_gate(gate.clone());
+
+	let b1 = mine_block(&gen, reward_key);
+	gate.pause();
+	assert!(gate.is_paused());
+	match grin_chain::process_block(&b1, arc_store.clone(), adapter.clone(), &pipeline) {
+		Err(grin_chain::pipe::Error::Paused) => {}
+		_ => panic!("should refuse blocks while paused"),
+	}
+	// reads still go through
+	assert_eq!(arc_store.head().unwrap().last_block_h, gen.hash());
+	assert!(!arc_store.contains_block(&b1.hash()).unwrap());
+
+	gate.resume();
+	assert!(!gate.is_paused());
+	grin_chain::process_block(&b1, arc_store.clone(), adapter.clone(), &pipeline).unwrap();
+	assert_eq!(arc_store.head().unwrap().last_block_h, b1.hash());
+	// pausing with nothing in flight returns right away
+	gate.pause();
+	gate.resume();
+}
+
+#[test]
+fn rate_limited_sync_stalls() {
+	let Setup { _dir, gen, store: arc_store, opts, adapter, reward_key } = setup("grin_rate_limit");
+	// a single block per peer, never refilled
+	let limiter = Arc::new(grin_chain::RateLimiter::new(0, 1));
+	let pipeline = opts.clone().with_rate_limiter(limiter);
+
+	let b1 = mine_block(&gen, reward_key);
+	let b2 = mine_block(&b1, reward_key);
+	let b3 = mine_block(&b2, reward_key);
+	grin_chain::process_block_from(&b1, 7, arc_store.clone(), adapter.clone(), &pipeline).unwrap();
+	match grin_chain::process_block_from(&b2, 7, arc_store.clone(), adapter.clone(), &pipeline) {
+		Err(grin_chain::pipe::Error::RateLimited) => {}
+		_ => panic!("should rate limit the peer"),
+	}
+	assert!(!arc_store.contains_block(&b2.hash()).unwrap());
+	// another peer has its own quota
+	grin_chain::process_block_from(&b2, 8, arc_store.clone(), adapter.clone(), &pipeline).unwrap();
+	assert_eq!(arc_store.head().unwrap().height, 2);
+
+	let progress = arc_store.progress_since(0, 1000, 1010).unwrap();
+	assert_eq!(progress.blocks, 2);
+	assert!(!progress.stalled);
+
+	// all peers limited, the head stops moving
+	match grin_chain::process_block_from(&b3, 8, arc_store.clone(), adapter.clone(), &pipeline) {
+		Err(grin_chain::pipe::Error::RateLimited) => {}
+		_ => panic!("should rate limit the peer"),
+	}
+	let progress = arc_store.progress_since(2, 1010, 1010 + SYNC_STALL_SECS - 1).unwrap();
+	assert!(!progress.stalled);
+	let progress = arc_store.progress_since(2, 1010, 1010 + SYNC_STALL_SECS).unwrap();
+	assert_eq!(progress.blocks, 0);
+	assert!(progress.stalled);
+
+	// without a limiter, blocks from the same peer go through again
+	grin_chain::process_block_from(&b3, 8, arc_store.clone(), adapter.clone(), &opts).unwrap();
+	let progress = arc_store.progress_since(2, 1010, 1010 + SYNC_STALL_SECS).unwrap();
+	assert_eq!(progress.blocks, 1);
+	assert!(!progress.stalled);
+}
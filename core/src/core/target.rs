@@ -16,10 +16,12 @@
 //!
 
 use byteorder::{ByteOrder, BigEndian};
+use std::cmp;
 use std::fmt;
 use std::ops::{Add, Sub, Shl, Shr, Index, IndexMut};
 use tiny_keccak::Keccak;
 
+use consensus::MAX_TARGET;
 use ser::{self, Reader, Writer, Writeable, Readable};
 
 /// A Bitcoin-style target, implemented as a 32 bytes positive big number that
@@ -215,6 +217,72 @@ fn sub_with_carryover(a: u8, b: u8, carry: u8) -> (u8, u8) {
 	(diff, new_carry)
 }
 
+/// The difficulty of a target, expressed as how many times harder it is to
+/// find a proof of work under it than under MAX_TARGET. Unlike targets,
+/// difficulties add up, the total difficulty of a chain being the sum of the
+/// difficulties of its blocks.
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Eq, Ord)]
+pub struct Difficulty(pub u64);
+
+impl Difficulty {
+	/// The difficulty of the provided target, at least one for targets up to
+	/// MAX_TARGET.
+	pub fn from_target(t: &Target) -> Difficulty {
+		let (max_exp, max_mantissa) = MAX_TARGET.split();
+		let (exp, mantissa) = t.split();
+		if mantissa == 0 {
+			return Difficulty(::std::u64::MAX);
+		}
+		if exp > max_exp {
+			return Difficulty(1);
+		}
+		// MAX_TARGET / t, keeping within u64 for very small targets
+		let shift = (max_exp - exp) as u32;
+		let d = if shift <= 32 {
+			((max_mantissa as u64) << shift) / (mantissa as u64)
+		} else {
+			let d = ((max_mantissa as u64) << 32) / (mantissa as u64);
+			if d.leading_zeros() < shift - 32 {
+				::std::u64::MAX
+			} else {
+				d << (shift - 32)
+			}
+		};
+		Difficulty(cmp::max(d, 1))
+	}
+
+	/// Sum of both difficulties, None if it doesn't fit. A u64 sums up a
+	/// chain of millions of blocks of difficulties well above the current
+	/// ones, only absurd difficulties get there.
+	pub fn checked_add(self, other: Difficulty) -> Option<Difficulty> {
+		self.0.checked_add(other.0).map(Difficulty)
+	}
+
+	/// Difference between both difficulties, None if the other one is
+	/// higher.
+	pub fn checked_sub(self, other: Difficulty) -> Option<Difficulty> {
+		self.0.checked_sub(other.0).map(Difficulty)
+	}
+}
+
+impl fmt::Display for Difficulty {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
+impl Writeable for Difficulty {
+	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		writer.write_u64(self.0)
+	}
+}
+
+impl Readable<Difficulty> for Difficulty {
+	fn read(reader: &mut Reader) -> Result<Difficulty, ser::Error> {
+		Ok(Difficulty(try!(reader.read_u64())))
+	}
+}
+
 #[cfg(test)]
 mod test {
 	use super::*;
@@ -300,4 +368,15 @@ mod test {
 		assert_eq!(Target::join(0, 0xffff).unwrap() - Target::join(0, 0xff01).unwrap(),
 		           Target::join(0, 0xfe).unwrap());
 	}
+
+	#[test]
+	fn difficulty_from_target() {
+		assert_eq!(Difficulty::from_target(&MAX_TARGET), Difficulty(1));
+		assert_eq!(Difficulty::from_target(&(MAX_TARGET >> 8)), Difficulty(256));
+		assert_eq!(Difficulty::from_target(&(MAX_TARGET >> 40)), Difficulty(1 << 40));
+		// easier than the max target still counts for something
+		assert_eq!(Difficulty::from_target(&(MAX_TARGET << 1)), Difficulty(1));
+		assert!(Difficulty::from_target(&(MAX_TARGET >> 20)) >
+		        Difficulty::from_target(&(MAX_TARGET >> 19)));
+	}
 }
@@ -12,10 +12,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 
 use chain::{self, ChainAdapter};
 use core::core;
+use core::core::hash::Hashed;
 use p2p::{NetAdapter, Server};
 use util::OneTime;
 
@@ -27,20 +31,33 @@ pub struct NetToChainAdapter {
 	chain_head: Arc<Mutex<chain::Tip>>,
 	chain_store: Arc<chain::ChainStore>,
 	chain_adapter: Arc<ChainToNetAdapter>,
+	/// quota of blocks for each peer, shared by all our peers connections
+	limiter: Arc<chain::RateLimiter>,
 }
 
 impl NetAdapter for NetToChainAdapter {
 	fn transaction_received(&self, tx: core::Transaction) {
 		unimplemented!();
 	}
-	fn block_received(&self, b: core::Block) {
+	fn block_received(&self, b: core::Block, addr: SocketAddr) {
 		// TODO delegate to a separate thread to avoid holding up the caller
-		debug!("Received block {} from network, going to process.",
-		       b.hash());
+		debug!("Received block {} from {}, going to process.", b.hash(), addr);
+		// peers keep relaying blocks we already have, skip those early
+		if self.chain_store.probably_have(&b.hash()) {
+			match self.chain_store.contains_block(&b.hash()) {
+				Ok(true) => {
+					debug!("Block {} already known, skipping.", b.hash());
+					return;
+				}
+				Ok(false) => {}
+				Err(e) => debug!("Could not check block {} is known: {:?}", b.hash(), e),
+			}
+		}
 		// pushing the new block through the chain pipeline
 		let store = self.chain_store.clone();
 		let chain_adapter = self.chain_adapter.clone();
-		let res = chain::process_block(&b, store, chain_adapter, chain::NONE);
+		let opts = chain::PipelineOpts::new(chain::NONE).with_rate_limiter(self.limiter.clone());
+		let res = chain::process_block_from(&b, source_of(&addr), store, chain_adapter, &opts);
 
 		// log errors and update the shared head reference on success
 		if let Err(e) = res {
@@ -62,10 +79,21 @@ impl NetToChainAdapter {
 			chain_head: chain_head,
 			chain_store: chain_store,
 			chain_adapter: chain_adapter,
+			limiter: Arc::new(chain::RateLimiter::new(chain::limiter::DEFAULT_RATE,
+			                                          chain::limiter::DEFAULT_BURST)),
 		}
 	}
 }
 
+// Rate limiting source of the blocks from the peer at the provided address.
+// Only the IP is used so reconnecting from another port doesn't give a fresh
+// quota.
+fn source_of(addr: &SocketAddr) -> chain::PeerId {
+	let mut hasher = DefaultHasher::new();
+	addr.ip().hash(&mut hasher);
+	hasher.finish()
+}
+
 /// Implementation of the ChainAdapter for the network. Gets notified when the
 /// blockchain accepted a new block and forwards it to the network for
 /// broadcast.
@@ -77,6 +105,17 @@ impl ChainAdapter for ChainToNetAdapter {
 	fn block_accepted(&self, b: &core::Block) {
 		self.p2p.borrow().broadcast_block(b);
 	}
+
+	fn reorged(&self, txs: &chain::types::ReorgTxSet) {
+		// TODO reconcile with the transaction pool once we have one
+		debug!("Reorg with {} transactions to add back and {} to remove.",
+		       txs.to_readd.len(),
+		       txs.to_remove.len());
+	}
+
+	fn block_finalized(&self, header: &core::BlockHeader) {
+		debug!("Block {} at {} finalized.", header.hash(), header.height);
+	}
 }
 
 impl ChainToNetAdapter {
@@ -99,7 +99,7 @@ impl Miner {
 				let res = chain::process_block(&b,
 				                               self.chain_store.clone(),
 				                               self.chain_adapter.clone(),
-				                               chain::NONE);
+				                               &chain::PipelineOpts::new(chain::NONE));
 				if let Err(e) = res {
 					error!("Error validating mined block: {:?}", e);
 				} else if let Ok(Some(tip)) = res {
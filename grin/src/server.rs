@@ -191,21 +191,29 @@ impl ServerFut {
 fn store_head(config: &ServerConfig)
               -> Result<(Arc<chain::store::ChainKVStore>, chain::Tip), Error> {
 	let chain_store = try!(chain::store::ChainKVStore::new(config.db_root.clone())
+		.and_then(|s| s.with_seen_filter(chain::store::SeenFilterPolicy::default()))
 		.map_err(&Error::StoreErr));
 
 	// check if we have a head in store, otherwise the genesis block is it
 	let head = match chain_store.head() {
-		Ok(tip) => tip,
+		Ok(tip) => {
+			// make sure a block body lost since last run doesn't prevent us from
+			// starting
+			match chain_store.head_block() {
+				Err(chain::types::Error::HeadBodyMissing(_)) => {
+					try!(chain_store.repair_head().map_err(&Error::StoreErr))
+				}
+				Err(e) => return Err(Error::StoreErr(e)),
+				Ok(_) => tip,
+			}
+		}
 		Err(chain::types::Error::NotFoundErr) => {
 			debug!("No genesis block found, creating and saving one.");
 			let mut gen = core::genesis::genesis();
 			if config.cuckoo_size > 0 {
 				gen.header.cuckoo_len = config.cuckoo_size;
 			}
-			try!(chain_store.save_block(&gen).map_err(&Error::StoreErr));
-			let tip = chain::types::Tip::new(gen.hash());
-			try!(chain_store.save_head(&tip).map_err(&Error::StoreErr));
-			tip
+			try!(chain_store.init(&gen).map_err(&Error::StoreErr))
 		}
 		Err(e) => return Err(Error::StoreErr(e)),
 	};
@@ -14,6 +14,7 @@
 
 use std::cell::RefCell;
 use std::iter;
+use std::net::SocketAddr;
 use std::ops::DerefMut;
 use std::sync::{Mutex, Arc};
 
@@ -58,6 +59,7 @@ impl Protocol for ProtocolV1 {
 	          conn: TcpStream,
 	          adapter: Arc<NetAdapter>)
 	          -> Box<Future<Item = (), Error = ser::Error>> {
+		let addr = conn.peer_addr().unwrap();
 		let (reader, writer) = conn.split();
 
 		// prepare the channel that will transmit data to the connection writer
@@ -68,7 +70,7 @@ impl Protocol for ProtocolV1 {
 		}
 
 		// setup the reading future, getting messages from the peer and processing them
-		let read_msg = self.read_msg(tx, reader, adapter).map(|_| ());
+		let read_msg = self.read_msg(tx, reader, adapter, addr).map(|_| ());
 
 		// setting the writing future, getting messages from our system and sending
 		// them out
@@ -109,11 +111,13 @@ impl Protocol for ProtocolV1 {
 
 impl ProtocolV1 {
 	/// Prepares the future reading from the peer connection, parsing each
-	/// message and forwarding them appropriately based on their type
+	/// message and forwarding them appropriately based on their type, along
+	/// with the peer address
 	fn read_msg(&self,
 	            sender: UnboundedSender<Vec<u8>>,
 	            reader: ReadHalf<TcpStream>,
-	            adapter: Arc<NetAdapter>)
+	            adapter: Arc<NetAdapter>,
+	            addr: SocketAddr)
 	            -> Box<Future<Item = ReadHalf<TcpStream>, Error = ser::Error>> {
 
 		// infinite iterator stream so we repeat the message reading logic until the
@@ -146,7 +150,7 @@ impl ProtocolV1 {
 					*recv_bytes += header.serialized_len() + header.msg_len;
 
 					// and handle the different message types
-					if let Err(e) = handle_payload(adapter, &header, buf, &mut sender_inner) {
+					if let Err(e) = handle_payload(adapter, addr, &header, buf, &mut sender_inner) {
 						debug!("Invalid {:?} message: {}", header.msg_type, e);
 					}
 
@@ -195,6 +199,7 @@ impl ProtocolV1 {
 }
 
 fn handle_payload(adapter: Arc<NetAdapter>,
+                  addr: SocketAddr,
                   header: &MsgHeader,
                   buf: Vec<u8>,
                   sender: &mut UnboundedSender<Vec<u8>>)
@@ -211,7 +216,7 @@ fn handle_payload(adapter: Arc<NetAdapter>,
 		}
 		Type::Block => {
 			let b = try!(ser::deserialize::<core::Block>(&mut &buf[..]));
-			adapter.block_received(b);
+			adapter.block_received(b, addr);
 		}
 		_ => {
 			debug!("unknown message type {:?}", header.msg_type);
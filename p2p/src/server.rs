@@ -37,7 +37,7 @@ use types::*;
 pub struct DummyAdapter {}
 impl NetAdapter for DummyAdapter {
 	fn transaction_received(&self, tx: core::Transaction) {}
-	fn block_received(&self, b: core::Block) {}
+	fn block_received(&self, b: core::Block, addr: SocketAddr) {}
 }
 
 /// P2P server implementation, handling bootstrapping to find and connect to
@@ -94,6 +94,6 @@ pub trait NetAdapter {
 	/// A valid transaction has been received from one of our peers
 	fn transaction_received(&self, tx: core::Transaction);
 
-	/// A block has been received from one of our peers
-	fn block_received(&self, b: core::Block);
+	/// A block has been received from the peer at the provided address
+	fn block_received(&self, b: core::Block, addr: SocketAddr);
 }
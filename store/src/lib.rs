@@ -27,7 +27,7 @@ use std::sync::RwLock;
 
 use core::ser;
 
-use rocksdb::{DB, Options, Writable, DBCompactionStyle};
+use rocksdb::{DB, Direction, IteratorMode, Options, Writable, WriteBatch, DBCompactionStyle};
 
 /// Main error type for this crate.
 #[derive(Debug)]
@@ -71,6 +71,35 @@ impl Store {
 		db.put(key, &value[..]).map_err(Error::RocksDbErr)
 	}
 
+	/// Writes all the provided key/value pairs to the db at once, in a
+	/// single atomic batch.
+	pub fn put_batch(&self, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<(), Error> {
+		let db = self.rdb.write().unwrap();
+		let batch = WriteBatch::new();
+		for &(ref key, ref value) in entries {
+			try!(batch.put(&key[..], &value[..]));
+		}
+		db.write(batch).map_err(Error::RocksDbErr)
+	}
+
+	/// Deletes the provided keys then writes the provided key/value pairs, all
+	/// in a single atomic batch. A key both deleted and written ends up with
+	/// the written value.
+	pub fn write_batch(&self,
+	                   deletes: &[Vec<u8>],
+	                   entries: &[(Vec<u8>, Vec<u8>)])
+	                   -> Result<(), Error> {
+		let db = self.rdb.write().unwrap();
+		let batch = WriteBatch::new();
+		for key in deletes {
+			try!(batch.delete(&key[..]));
+		}
+		for &(ref key, ref value) in entries {
+			try!(batch.put(&key[..], &value[..]));
+		}
+		db.write(batch).map_err(Error::RocksDbErr)
+	}
+
 	/// Writes a single key and its `Writeable` value to the db. Encapsulates
 	/// serialization.
 	pub fn put_ser(&self, key: &[u8], value: &ser::Writeable) -> Result<(), Error> {
@@ -100,7 +129,10 @@ impl Store {
 	                                            key: &[u8],
 	                                            len: usize)
 	                                            -> Result<Option<T>, Error> {
-		let data = try!(self.get(key));
+		// deserializes straight from the buffer handed over by the db instead of
+		// copying it first
+		let db = self.rdb.read().unwrap();
+		let data = try!(db.get(key).map_err(Error::RocksDbErr));
 		match data {
 			Some(val) => {
 				let mut lval = if len > 0 { &val[..len] } else { &val[..] };
@@ -111,6 +143,66 @@ impl Store {
 		}
 	}
 
+	/// Gets a `Readable` value from the db, provided its key, going through
+	/// the provided scratch buffer. Reusing the same buffer over many reads
+	/// saves allocating for each value. Encapsulates serialization.
+	pub fn get_ser_buf<T: ser::Readable<T>>(&self,
+	                                        key: &[u8],
+	                                        buf: &mut Vec<u8>)
+	                                        -> Result<Option<T>, Error> {
+		{
+			let db = self.rdb.read().unwrap();
+			match try!(db.get(key).map_err(Error::RocksDbErr)) {
+				Some(val) => {
+					buf.clear();
+					buf.extend_from_slice(&val[..]);
+				}
+				None => return Ok(None),
+			}
+		}
+		let r = try!(ser::deserialize(&mut &buf[..]).map_err(Error::SerErr));
+		Ok(Some(r))
+	}
+
+	/// Gets all `Readable` values whose keys start with the provided prefix,
+	/// in key order. Encapsulates serialization.
+	pub fn get_ser_prefix<T: ser::Readable<T>>(&self, prefix: &[u8]) -> Result<Vec<T>, Error> {
+		self.get_ser_from(prefix, prefix)
+	}
+
+	/// Gets all `Readable` values whose keys start with the provided prefix
+	/// and are greater or equal to the provided key, in key order.
+	pub fn get_ser_from<T: ser::Readable<T>>(&self,
+	                                         prefix: &[u8],
+	                                         from: &[u8])
+	                                         -> Result<Vec<T>, Error> {
+		let db = self.rdb.read().unwrap();
+		let mut res = vec![];
+		for (key, val) in db.iterator(IteratorMode::From(from, Direction::Forward)) {
+			if !key.starts_with(prefix) {
+				break;
+			}
+			let mut lval = &val[..];
+			res.push(try!(ser::deserialize(&mut lval).map_err(Error::SerErr)));
+		}
+		Ok(res)
+	}
+
+	/// Calls the provided function with the key of each entry whose key
+	/// starts with the provided prefix, in key order. Nothing is collected,
+	/// so memory use doesn't depend on the number of entries.
+	pub fn for_each_key<F>(&self, prefix: &[u8], mut f: F)
+		where F: FnMut(&[u8])
+	{
+		let db = self.rdb.read().unwrap();
+		for (key, _) in db.iterator(IteratorMode::From(prefix, Direction::Forward)) {
+			if !key.starts_with(prefix) {
+				break;
+			}
+			f(&key);
+		}
+	}
+
 	/// Deletes a key/value pair from the db
 	pub fn delete(&self, key: &[u8]) -> Result<(), Error> {
 		let db = self.rdb.write().unwrap();